@@ -0,0 +1,60 @@
+-- General helpers shipped with the server, loaded with `require "lurk.util"`.
+
+local util = {}
+
+-- Splits a string on a plain separator, keeping empty fields.
+function util.split(s, sep)
+  local fields = {}
+  local start = 1
+  while true do
+    local i, j = string.find(s, sep, start, true)
+    if not i then
+      table.insert(fields, string.sub(s, start))
+      return fields
+    end
+    table.insert(fields, string.sub(s, start, i - 1))
+    start = j + 1
+  end
+end
+
+function util.trim(s)
+  return (string.gsub(s, "^%s*(.-)%s*$", "%1"))
+end
+
+function util.starts_with(s, prefix)
+  return string.sub(s, 1, #prefix) == prefix
+end
+
+function util.clamp(n, low, high)
+  return math.max(low, math.min(high, n))
+end
+
+-- A copy of a table one level deep.
+function util.copy(t)
+  local copy = {}
+  for k, v in pairs(t) do
+    copy[k] = v
+  end
+  return copy
+end
+
+-- The table's keys in sorted order.
+function util.keys(t)
+  local keys = {}
+  for k in pairs(t) do
+    table.insert(keys, k)
+  end
+  table.sort(keys, function(a, b) return tostring(a) < tostring(b) end)
+  return keys
+end
+
+function util.contains(list, value)
+  for _, v in ipairs(list) do
+    if v == value then
+      return true
+    end
+  end
+  return false
+end
+
+return util
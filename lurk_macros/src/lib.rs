@@ -91,3 +91,93 @@ pub fn lurk_readable(input: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+fn field_attribute(attrs: &[Attribute], name: &str) -> Option<Lit> {
+    use quote::ToTokens;
+    for attr in attrs.iter() {
+        if let Ok(Meta::NameValue(MetaNameValue { path, lit, .. })) = attr.parse_meta() {
+            if path.to_token_stream().to_string() == name {
+                return Some(lit);
+            }
+        }
+    }
+    None
+}
+
+/// Maps a struct's fields to Lua field names for the `LuaFields` trait. A field is
+/// named after itself unless it has a `#[LuaName = "..."]` attribute, and a field
+/// marked `#[LuaFlatten = true]` has its own `LuaFields` merged into the parent's.
+#[proc_macro_derive(LuaFields, attributes(LuaName, LuaFlatten))]
+pub fn lua_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let fields = match &input.data {
+        syn::Data::Struct(data) => &data.fields,
+        _ => panic!("LuaFields can only be derived for structs."),
+    };
+
+    let mut idents = vec![];
+    let mut lua_names = vec![];
+    let mut flattened = vec![];
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().expect("LuaFields needs named fields.");
+        match field_attribute(&field.attrs, "LuaFlatten") {
+            Some(Lit::Bool(flatten)) if flatten.value => {
+                flattened.push(ident);
+                continue;
+            }
+            Some(Lit::Bool(_)) | None => {}
+            Some(_) => panic!("LuaFlatten must be a valid bool."),
+        }
+        let lua_name = match field_attribute(&field.attrs, "LuaName") {
+            Some(Lit::Str(name)) => name.value(),
+            Some(_) => panic!("LuaName must be a string."),
+            None => ident.to_string(),
+        };
+        idents.push(ident);
+        lua_names.push(lua_name);
+    }
+
+    let name = &input.ident;
+    let type_name = name.to_string();
+    let expanded = quote! {
+        impl LuaFields for #name {
+            fn type_name() -> &'static str {
+                #type_name
+            }
+
+            fn get_field<'lua>(&self, ctx: rlua::Context<'lua>, key: &str) -> rlua::Result<Option<rlua::Value<'lua>>> {
+                match key {
+                    #( #lua_names => return LuaField::to_lua_field(&self.#idents, ctx).map(Some), )*
+                    _ => {}
+                }
+                #(
+                    if let Some(value) = LuaFields::get_field(&self.#flattened, ctx, key)? {
+                        return Ok(Some(value));
+                    }
+                )*
+                Ok(None)
+            }
+
+            fn set_field<'lua>(&mut self, ctx: rlua::Context<'lua>, key: &str, value: rlua::Value<'lua>) -> rlua::Result<bool> {
+                match key {
+                    #(
+                        #lua_names => {
+                            self.#idents = LuaField::from_lua_field(value, ctx)?;
+                            return Ok(true);
+                        }
+                    )*
+                    _ => {}
+                }
+                #(
+                    if LuaFields::set_field(&mut self.#flattened, ctx, key, value.clone())? {
+                        return Ok(true);
+                    }
+                )*
+                Ok(false)
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
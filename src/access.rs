@@ -0,0 +1,257 @@
+use crate::protocol::Error;
+use crate::session::Sessions;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A block of addresses, written `address/prefix`. A bare address is a block of one.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                same_prefix(u128::from(u32::from(network)), u128::from(u32::from(ip)), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(network), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let (address, prefix) = match text.find('/') {
+            Some(slash) => (&text[..slash], Some(&text[slash + 1..])),
+            None => (text, None),
+        };
+        let network = address.parse::<IpAddr>()
+            .map(canonical)
+            .map_err(|_| format!("'{}' is not an IP address", address))?;
+        let width = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("'{}' is not a prefix length between 0 and {}", prefix, width))?,
+            None => width,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// IPv4 peers on a dual-stack listener show up as mapped IPv6 addresses. This is
+/// the address they'd have on an IPv4 listener.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+fn same_prefix(a: u128, b: u128, width: u8, prefix: u8) -> bool {
+    let shift = u32::from(width - prefix);
+    shift >= 128 || a >> shift == b >> shift
+}
+
+/// Blocks of addresses that may and may not connect. Denied blocks win over
+/// allowed ones, and an empty allow list allows everyone not denied.
+#[derive(Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Reads a list with one `allow <block>` or `deny <block>` per line. Blank lines
+    /// and anything after a '#' are ignored.
+    pub fn load(path: &Path) -> Result<AccessList, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut list = AccessList::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (rule, block) = (words.next(), words.next());
+            let parsed = match (rule, block, words.next()) {
+                (Some("allow"), Some(block), None) => block.parse().map(|cidr| list.allow.push(cidr)),
+                (Some("deny"), Some(block), None) => block.parse().map(|cidr| list.deny.push(cidr)),
+                _ => Err("expected 'allow <block>' or 'deny <block>'".to_string()),
+            };
+            parsed.map_err(|e| format!("line {}: {}", number + 1, e))?;
+        }
+        Ok(list)
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+/// The access list in force, shared between the network, which checks it, and
+/// the game thread, which reloads it.
+#[derive(Clone, Default)]
+pub struct AccessHandle {
+    path: Option<PathBuf>,
+    list: Arc<Mutex<AccessList>>,
+}
+
+impl AccessHandle {
+    /// Loads the list at the path, or allows everyone if there's no path.
+    pub fn open(path: Option<&Path>) -> Result<AccessHandle, String> {
+        let list = match path {
+            Some(path) => AccessList::load(path)?,
+            None => AccessList::default(),
+        };
+        Ok(AccessHandle {
+            path: path.map(Path::to_path_buf),
+            list: Arc::new(Mutex::new(list)),
+        })
+    }
+
+    /// Reads the list from its file again. The old list stays in force if that fails.
+    pub fn reload(&self) -> Result<(), String> {
+        if let Some(path) = &self.path {
+            *self.list.lock().unwrap() = AccessList::load(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.list.lock().unwrap().permits(ip)
+    }
+}
+
+/// Notices when the access list's file is modified, so it's reloaded without
+/// waiting for a SIGHUP.
+pub struct ListWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ListWatcher {
+    pub fn new(path: &Path) -> ListWatcher {
+        ListWatcher { path: path.to_path_buf(), modified: modified(path) }
+    }
+
+    pub fn changed(&mut self) -> bool {
+        let current = modified(&self.path);
+        if current == self.modified {
+            return false;
+        }
+        self.modified = current;
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Who may connect and how many may be connected at once. `None` is no limit.
+#[derive(Clone)]
+pub struct Admission {
+    pub max_clients: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    pub access: AccessHandle,
+}
+
+impl Admission {
+    /// Decides whether a new connection may join the clients already in `sessions`,
+    /// giving the error to turn it away with if not.
+    pub fn check(&self, peer: Option<SocketAddr>, sessions: &Sessions) -> Result<(), Error> {
+        if let Some(ip) = peer.map(|peer| peer.ip()) {
+            if !self.access.permits(ip) {
+                return Err(Error::new(Error::OTHER, "Connections from your address are not allowed."));
+            }
+        }
+        if matches!(self.max_clients, Some(limit) if sessions.ids().len() >= limit) {
+            return Err(Error::new(Error::OTHER, "The server is full."));
+        }
+        if let (Some(limit), Some(ip)) = (self.max_per_ip, peer.map(|peer| peer.ip())) {
+            if sessions.count_from(ip) >= limit {
+                return Err(Error::new(Error::OTHER, "Too many connections from your address."));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(text: &str) -> Cidr {
+        text.parse().unwrap()
+    }
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn bare_addresses_are_blocks_of_one() {
+        assert!(cidr("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(cidr("10.1.2.3/32").contains(ip("10.1.2.3")));
+        assert!(!cidr("10.1.2.3/32").contains(ip("10.1.2.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn prefixes_match_whole_blocks() {
+        assert!(cidr("192.168.0.0/16").contains(ip("192.168.200.7")));
+        assert!(!cidr("192.168.0.0/16").contains(ip("192.169.0.1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn zero_prefixes_match_a_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("::/0").contains(ip("ffff::1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn mapped_ipv4_is_treated_as_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.9.8.7")));
+        assert!(cidr("::ffff:10.0.0.0/8").contains(ip("10.9.8.7")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+    }
+
+    #[test]
+    fn bad_blocks_are_refused() {
+        for text in &["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/x", "10.0.0/8", "nowhere"] {
+            assert!(text.parse::<Cidr>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn same_prefix_compares_only_the_prefix() {
+        assert!(same_prefix(0xff00, 0xff0f, 16, 12));
+        assert!(!same_prefix(0xff00, 0xff1f, 16, 12));
+        assert!(same_prefix(0, u128::MAX, 128, 0));
+        assert!(same_prefix(0, u128::from(u32::MAX), 32, 0));
+        assert!(!same_prefix(0, 1, 128, 128));
+    }
+}
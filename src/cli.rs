@@ -1,11 +1,216 @@
-use clap::{App, Arg, Clap};
+use crate::client::SlowClient;
+use crate::log::{Filter, Format, Level};
+use crate::rate::RateLimit;
+use std::net::SocketAddr;
+use clap::Clap;
 
 #[derive(Clap)]
 #[clap(version = "1.0",  author = "Austin Jenkins")]
 pub struct Args {
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Run the game server.
+    Serve(ServeArgs),
+    /// Check a module for problems without starting the server.
+    Validate(ValidateArgs),
+    /// Work with the server's configuration.
+    Config(ConfigArgs),
+}
+
+/// Flags left out fall back to the config file, then to the built-in defaults.
+#[derive(Clap)]
+pub struct ServeArgs {
+    /// TOML file to read the configuration from. Flags override what it says.
+    #[clap(short = "c", long = "config")]
+    pub config: Option<String>,
+
+    /// Path to the module directory containing 'main.lua'.
     #[clap(short = "m", long = "module")]
-    pub module: String,
+    pub module: Option<String>,
 
+    /// Port to listen on at every IPv4 address when no '--listen' is given.
     #[clap(short = "p", long = "port")]
-    pub port: u16,
+    pub port: Option<u16>,
+
+    /// Address to accept clients on, e.g. '0.0.0.0:5000' or '[::]:5000'. May be repeated.
+    #[clap(long = "listen", number_of_values = 1)]
+    pub listen: Vec<SocketAddr>,
+
+    /// Address to accept clients on without the connection limits or access list,
+    /// e.g. '127.0.0.1:5001' for local admin tools. May be repeated.
+    #[clap(long = "trusted-listen", number_of_values = 1)]
+    pub trusted_listen: Vec<SocketAddr>,
+
+    /// Keep IPv6 listeners to IPv6 clients. Otherwise a listener on '[::]' takes IPv4
+    /// clients too.
+    #[clap(long = "ipv6-only", overrides_with = "no-ipv6-only")]
+    pub ipv6_only: bool,
+
+    /// Let IPv6 listeners take IPv4 clients, even if the config file says otherwise.
+    #[clap(long = "no-ipv6-only", overrides_with = "ipv6-only")]
+    pub no_ipv6_only: bool,
+
+    /// Milliseconds between server ticks. Defaults to 100.
+    #[clap(long = "tick-ms")]
+    pub tick_ms: Option<u64>,
+
+    /// Ticks between saves of every character in play. Defaults to 600.
+    #[clap(long = "save-interval")]
+    pub save_interval: Option<u32>,
+
+    /// Key-value store journal for the module's 'Store' global.
+    /// Defaults to 'store.journal' in the module directory.
+    #[clap(long = "store")]
+    pub store: Option<String>,
+
+    /// Directory saved characters are kept in. Defaults to 'saves' in the module directory.
+    #[clap(long = "saves")]
+    pub saves: Option<String>,
+
+    /// Lua instructions one hook call may run before it is aborted, zero for no limit.
+    /// Defaults to 10000000.
+    #[clap(long = "max-instructions")]
+    pub max_instructions: Option<u64>,
+
+    /// Megabytes of memory module scripts may use in total, zero for no limit.
+    /// Defaults to 64.
+    #[clap(long = "max-memory-mb")]
+    pub max_memory_mb: Option<usize>,
+
+    /// Reload the module whenever a Lua file in it changes. SIGHUP always reloads.
+    #[clap(long = "watch", overrides_with = "no-watch")]
+    pub watch: bool,
+
+    /// Don't watch the module for changes, even if the config file says to.
+    #[clap(long = "no-watch", overrides_with = "watch")]
+    pub no_watch: bool,
+
+    /// Milliseconds clients are given to receive the shutdown notice after SIGINT or
+    /// SIGTERM before the server exits regardless. Defaults to 5000.
+    #[clap(long = "shutdown-grace-ms")]
+    pub shutdown_grace_ms: Option<u64>,
+
+    /// Messages waiting to be written to one client before it counts as slow, zero for no limit.
+    /// Defaults to 1024.
+    #[clap(long = "max-queue-messages")]
+    pub max_queue_messages: Option<usize>,
+
+    /// Bytes waiting to be written to one client before it counts as slow, zero for no limit.
+    /// Defaults to 1048576.
+    #[clap(long = "max-queue-bytes")]
+    pub max_queue_bytes: Option<usize>,
+
+    /// What to do with a slow client: 'drop' holds back Character updates, sending only
+    /// the latest for each character once the queue drains, and disconnects only if
+    /// other messages overflow. 'disconnect' drops the client at once.
+    /// Defaults to 'drop'.
+    #[clap(long = "slow-client")]
+    pub slow_client: Option<SlowClient>,
+
+    /// Seconds a client may stay silent before it sends Start or after it sends Leave,
+    /// zero for no limit. Defaults to 120.
+    #[clap(long = "lobby-timeout")]
+    pub lobby_timeout: Option<u64>,
+
+    /// Seconds a playing client may stay silent, zero for no limit. Defaults to 1800.
+    #[clap(long = "idle-timeout")]
+    pub idle_timeout: Option<u64>,
+
+    /// Seconds before an idle disconnect that the module's 'on_idle' hook is called.
+    /// Defaults to 60.
+    #[clap(long = "idle-warning")]
+    pub idle_warning: Option<u64>,
+
+    /// Seconds a connection may sit silent before TCP keepalive probes start.
+    /// Keepalive is off unless this is given.
+    #[clap(long = "keepalive")]
+    pub keepalive: Option<u64>,
+
+    /// Clients that may be connected at once, zero for no limit. Defaults to 1024.
+    #[clap(long = "max-clients")]
+    pub max_clients: Option<usize>,
+
+    /// Clients that may be connected at once from one IP address, zero for no limit.
+    /// Defaults to no limit.
+    #[clap(long = "max-clients-per-ip")]
+    pub max_clients_per_ip: Option<usize>,
+
+    /// File of 'allow <cidr>' and 'deny <cidr>' lines saying who may connect. It's
+    /// reloaded when it's modified, and on SIGHUP, which reloads the module as well.
+    /// Everyone may connect unless this is given.
+    #[clap(long = "access-list")]
+    pub access_list: Option<String>,
+
+    /// How fast clients may send one type of message, as 'type=rate/burst' with the
+    /// rate per second, e.g. 'message=4/8'. May be repeated; a zero rate lifts the
+    /// limit. Message, change_room, fight, pvp_fight and loot are limited unless given.
+    #[clap(long = "rate-limit", number_of_values = 1)]
+    pub rate_limits: Vec<RateLimit>,
+
+    /// Times a client may go over a rate limit within the rate window before it's
+    /// kicked, zero to never kick. Defaults to 10.
+    #[clap(long = "rate-strikes")]
+    pub rate_strikes: Option<u32>,
+
+    /// Seconds over which rate limit strikes are forgiven, and the least time between
+    /// telling a client it's over the limits. Defaults to 60.
+    #[clap(long = "rate-window")]
+    pub rate_window: Option<u64>,
+
+    /// Least important records to log: error, warn, info, debug or trace. Defaults to info.
+    #[clap(long = "log-level")]
+    pub log_level: Option<Level>,
+
+    /// How records are written: 'text' or 'json'. Defaults to text.
+    #[clap(long = "log-format")]
+    pub log_format: Option<Format>,
+
+    /// A level for one module's records, as 'target=level', e.g. 'lurk_world::net=debug'.
+    /// Module scripts log as 'lua'. May be repeated.
+    #[clap(long = "log-filter", number_of_values = 1)]
+    pub log_filters: Vec<Filter>,
+}
+
+impl ServeArgs {
+    /// Whether '--ipv6-only' or '--no-ipv6-only' was given, whichever came last.
+    pub fn ipv6_only(&self) -> Option<bool> {
+        switch(self.ipv6_only, self.no_ipv6_only)
+    }
+
+    /// Whether '--watch' or '--no-watch' was given, whichever came last.
+    pub fn watch(&self) -> Option<bool> {
+        switch(self.watch, self.no_watch)
+    }
+}
+
+/// A flag and its '--no-' form, which override each other, so at most one is set.
+fn switch(on: bool, off: bool) -> Option<bool> {
+    if on || off { Some(on) } else { None }
+}
+
+#[derive(Clap)]
+pub struct ValidateArgs {
+    /// Path to the module directory containing 'main.lua'.
+    pub module: String,
+
+    /// TOML file whose '[lua]' limits to check the module under, as 'serve' would run
+    /// it with. Defaults to the built-in limits.
+    #[clap(short = "c", long = "config")]
+    pub config: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct ConfigArgs {
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Clap)]
+pub enum ConfigCommand {
+    /// Print the configuration 'serve' would run with given the same flags.
+    Check(ServeArgs),
 }
@@ -1,51 +1,154 @@
-use crate::read::{LurkPollEvent, LurkRead, LurkReadEvent};
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::net::TcpStream;
-use crate::read_buffer::ReadBuffer;
+use crate::access::Admission;
+use crate::net::{Connection, Outbound};
+use crate::read::LurkReadEvent;
+use std::net::SocketAddr;
+use crate::protocol::{Character, Error, LurkName};
+use crate::session::{ConnectionState, Disconnect, SessionHandle, Sessions};
+use crate::write::LurkWriteMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-pub struct ClientFactory {
-    id_cursor: u128,
+/// What happens to a client whose outbound queue is full.
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlowClient {
+    /// Hold back Character updates until the queue drains, sending only the latest
+    /// for each character, and disconnect only if anything else overflows.
+    Drop,
+    Disconnect,
 }
 
-impl Default for ClientFactory {
-    fn default() -> ClientFactory {
-        ClientFactory { id_cursor: 0 }
+impl FromStr for SlowClient {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<SlowClient, String> {
+        match name {
+            "drop" => Ok(SlowClient::Drop),
+            "disconnect" => Ok(SlowClient::Disconnect),
+            _ => Err(format!("expected 'drop' or 'disconnect', not '{}'", name)),
+        }
     }
 }
 
+/// How much may wait to be written to one client. `None` is no limit.
+#[derive(Clone, Copy)]
+pub struct QueueLimits {
+    pub messages: Option<usize>,
+    pub bytes: Option<usize>,
+    pub policy: SlowClient,
+}
+
+impl QueueLimits {
+    fn exceeded(&self, messages: usize, bytes: usize) -> bool {
+        matches!(self.messages, Some(limit) if messages > limit)
+            || matches!(self.bytes, Some(limit) if bytes > limit)
+    }
+}
+
+pub struct ClientFactory {
+    id_cursor: u128,
+    sessions: Sessions,
+    limits: QueueLimits,
+    admission: Admission,
+}
+
 impl ClientFactory {
-    pub fn create(&mut self, stream: TcpStream) -> Result<Client, ()> {
+    pub fn new(limits: QueueLimits, admission: Admission) -> ClientFactory {
+        ClientFactory { id_cursor: 0, sessions: Sessions::default(), limits, admission }
+    }
+
+    /// Decides whether a new connection may become a client, logging why not and
+    /// giving the error to send it before it's closed. Trusted connections always may.
+    pub fn admit(&self, peer: Option<SocketAddr>, trusted: bool) -> Result<(), Error> {
+        if trusted {
+            return Ok(());
+        }
+        self.admission.check(peer, &self.sessions).map_err(|error| {
+            let peer = peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+            warn!(peer = peer; "Turned away a connection: {}", String::from_utf8_lossy(&error.message));
+            error
+        })
+    }
+
+    /// Opens a session for a new connection and wraps it in the client the game
+    /// thread keeps.
+    pub fn create(&mut self, peer: Option<SocketAddr>, trusted: bool, connection: Connection) -> Client {
         self.id_cursor += 1;
-        let write_handle = stream.try_clone().map_err(|_| {})?;
-        Ok(Client {
+        let session = self.sessions.open(self.id_cursor, peer, trusted, connection.queue().clone());
+        Client {
             id: self.id_cursor,
-            read: stream.into(),
-            write: BufWriter::new(write_handle),
+            connection,
+            limits: self.limits,
+            last_active: Instant::now(),
+            warned: false,
+            held: HashMap::new(),
             poisoned: false,
-        })
+            kicked: None,
+            session,
+        }
+    }
+
+    /// Sessions of every client this factory created that's still around.
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
     }
 }
 
-const CLIENT_BUFFER_LIMIT: usize = 1024 * 1024;
+/// Undecoded bytes a client may have buffered before it's dropped.
+pub const CLIENT_BUFFER_LIMIT: usize = 1024 * 1024;
 
+/// A connected client as the game thread sees it. Dropping it closes the connection
+/// once whatever was already sent has been written.
 pub struct Client {
     id: u128,
-    read: ReadBuffer,
-    write: BufWriter<TcpStream>,
+    connection: Connection,
+    limits: QueueLimits,
+    last_active: Instant,
+    /// Whether the module has been told the client is about to time out.
+    warned: bool,
+    /// Character updates held back while the queue was full, the latest for each name.
+    held: HashMap<LurkName, Character>,
     poisoned: bool,
+    kicked: Option<Error>,
+    session: SessionHandle,
 }
 
 impl Client {
     pub fn id(&self) -> u128 {
         self.id
     }
+
+    pub fn state(&self) -> ConnectionState {
+        self.session.lock().unwrap().state
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        self.session.lock().unwrap().state = state;
+    }
+
+    /// Notes that the client has just sent something.
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+        self.warned = false;
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// True the first time it's called since the client was last active.
+    pub fn take_warning(&mut self) -> bool {
+        !std::mem::replace(&mut self.warned, true)
+    }
 }
 
 pub enum ClientEventKind {
     Read(LurkReadEvent),
     Join,
-    Left,
+    /// Carries the error the client was kicked with, if it was.
+    Left(Option<Error>),
 }
 
 pub struct ClientEvent {
@@ -58,60 +161,130 @@ impl ClientEvent {
         &self.event
     }
 
+    pub fn event_mut(&mut self) -> &mut ClientEventKind {
+        &mut self.event
+    }
+
     pub fn client_id(&self) -> u128 {
         self.client_id
     }
+
+    /// An event decoded from a client's connection.
+    pub fn read(client_id: u128, event: LurkReadEvent) -> ClientEvent {
+        ClientEvent {
+            event: ClientEventKind::Read(event),
+            client_id,
+        }
+    }
 }
 
 impl Client {
+    /// Marks the client for removal and cuts its connection, dropping anything
+    /// still waiting to be written.
     pub fn poison(&mut self) {
         self.poisoned = true;
+        self.connection.cut();
     }
 
     pub fn poisoned(&self) -> bool {
         self.poisoned
     }
 
-    pub fn poll_event(&mut self) -> Option<ClientEvent> {
-        if self.read.buffer().len() > CLIENT_BUFFER_LIMIT {
-            self.poison();
+    /// Queues the error for the client. The server then removes the client and
+    /// closes the connection once the error is delivered.
+    pub fn kick(&mut self, error: Error) {
+        self.send(LurkWriteMessage::Error(error.clone()));
+        self.set_state(ConnectionState::Leaving);
+        self.kicked = Some(error);
+    }
+
+    pub fn kicked(&self) -> bool {
+        self.kicked.is_some()
+    }
+
+    /// Whether events from the client should still reach the module.
+    pub fn listening(&self) -> bool {
+        !self.poisoned && !self.kicked()
+    }
+
+    /// Tells the client it sent a type of event faster than the rate limits allow.
+    pub fn throttle(&mut self, event: &str) {
+        if self.listening() {
+            let message = format!("You're sending '{}' too fast, slow down.", event);
+            self.send(LurkWriteMessage::Error(Error::new(Error::OTHER, &message)));
         }
+    }
 
-        if let Some(event) = self.poll_lurk() {
-            Some(ClientEvent {
-                event: ClientEventKind::Read(event),
-                client_id: self.id,
-            })
-        } else {
-            None
+    /// Kicks a client that kept going over the rate limits.
+    pub fn flooded(&mut self, event: &str) {
+        if self.listening() {
+            warn!(client = self.id; "Client kept sending '{}' too fast, disconnecting it.", event);
+            self.kick(Error::new(Error::OTHER, "Disconnected for flooding."));
         }
     }
 
-    fn poll_lurk(&mut self) -> Option<LurkReadEvent> {
-        if self.poisoned() {
-            return None;
+    /// Acts on a disconnect the module asked for through the client's session.
+    pub fn take_disconnect(&mut self) {
+        let disconnect = self.session.lock().unwrap().disconnect.take();
+        match disconnect {
+            Some(Disconnect::Kick(error)) => self.kick(error),
+            Some(Disconnect::Poison) => self.poison(),
+            None => {}
         }
+    }
 
-        if let Ok(event) = self.read.poll_lurk() {
-            match event {
-                LurkPollEvent::Pending => None,
-                LurkPollEvent::Message(msg) => Some(LurkReadEvent::Message(msg)),
-                LurkPollEvent::ChangeRoom(chgrm) => Some(LurkReadEvent::ChangeRoom(chgrm)),
-                LurkPollEvent::PVPFight(pvpfight) => Some(LurkReadEvent::PVPFight(pvpfight)),
-                LurkPollEvent::Loot(loot) => Some(LurkReadEvent::Loot(loot)),
-                LurkPollEvent::Character(ch) => Some(LurkReadEvent::Character(ch)),
-                LurkPollEvent::Fight => Some(LurkReadEvent::Fight),
-                LurkPollEvent::Start => Some(LurkReadEvent::Start),
-                LurkPollEvent::Leave => Some(LurkReadEvent::Leave),
-                LurkPollEvent::Version(vers) => Some(LurkReadEvent::Version(vers)),
-                LurkPollEvent::Bad => {
+    /// Has the connection shut gracefully after delivering what's queued, giving the
+    /// peer a moment to read it before the socket goes away.
+    pub fn close(self) {
+        self.connection.send(Outbound::Close);
+    }
+
+    /// Queues a message for the client, unless its queue is full. Then the message
+    /// is held back or the client disconnected, as the limits' policy says.
+    pub fn send(&mut self, lurkmsg: LurkWriteMessage) {
+        if self.poisoned {
+            return;
+        }
+        let queue = self.connection.queue();
+        let (messages, bytes) = (queue.messages() + 1, queue.bytes() + lurkmsg.wire_size());
+        if self.limits.exceeded(messages, bytes) {
+            match lurkmsg {
+                LurkWriteMessage::Character(character) if self.limits.policy == SlowClient::Drop => {
+                    // Only the latest update to a character is worth sending later.
+                    if self.held.insert(character.name, character).is_some() {
+                        queue.record_dropped();
+                    }
+                }
+                _ => {
+                    warn!(client = self.id; "Client isn't keeping up with {} messages ({} bytes) queued, disconnecting it.",
+                          queue.messages(), queue.bytes());
                     self.poison();
-                    None
                 }
             }
-        } else {
-            self.poison();
-            None
+            return;
+        }
+        if let LurkWriteMessage::Character(character) = &lurkmsg {
+            // Supersedes anything held back for the same character.
+            if self.held.remove(&character.name).is_some() {
+                queue.record_dropped();
+            }
+        }
+        if !self.connection.send(Outbound::Message(lurkmsg)) {
+            self.poisoned = true;
+        }
+    }
+
+    /// Sends the Character updates held back while the queue was full, as many as
+    /// it now has room for.
+    pub fn send_held(&mut self) {
+        while let Some(name) = self.held.keys().next().copied() {
+            let lurkmsg = LurkWriteMessage::Character(self.held[&name].clone());
+            let queue = self.connection.queue();
+            if self.poisoned || self.limits.exceeded(queue.messages() + 1, queue.bytes() + lurkmsg.wire_size()) {
+                return;
+            }
+            self.held.remove(&name);
+            self.send(lurkmsg);
         }
     }
 
@@ -124,7 +297,7 @@ impl Client {
 
     pub fn left(&self) -> ClientEvent {
         ClientEvent {
-            event: ClientEventKind::Left,
+            event: ClientEventKind::Left(self.kicked.clone()),
             client_id: self.id,
         }
     }
@@ -0,0 +1,279 @@
+use crate::module;
+use crate::protocol::{Character, CharacterFlags, Error, LurkName};
+use crate::world::{reject, Outgoing, WorldHandle};
+use crate::write::LurkWriteMessage;
+use rlua::Context;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Formula {
+    /// Attack minus defense, always at least one point.
+    Classic,
+    /// Attack scaled by attack over attack plus defense, so defense never blocks a hit outright.
+    Proportional,
+}
+
+impl Formula {
+    pub fn from_name(name: &str) -> Option<Formula> {
+        match name {
+            "classic" => Some(Formula::Classic),
+            "proportional" => Some(Formula::Proportional),
+            _ => None,
+        }
+    }
+
+    pub fn damage(self, attacker: &Character, defender: &Character) -> i16 {
+        // Wide enough for attack squared.
+        let attack = i64::from(attacker.attack);
+        let defense = i64::from(defender.defense);
+        let damage = match self {
+            Formula::Classic => attack - defense,
+            Formula::Proportional if attack + defense > 0 => attack * attack / (attack + defense),
+            Formula::Proportional => 0,
+        };
+        damage.max(1).min(i64::from(i16::MAX)) as i16
+    }
+}
+
+#[derive(Clone)]
+pub struct CombatRules {
+    pub formula: Formula,
+    /// Rounds fought per Fight or PVPFight message.
+    pub rounds: u16,
+    pub pvp: bool,
+}
+
+impl Default for CombatRules {
+    fn default() -> Self {
+        CombatRules {
+            formula: Formula::Classic,
+            rounds: 1,
+            pvp: true,
+        }
+    }
+}
+
+struct Fighter {
+    character: Character,
+    max_health: i16,
+}
+
+impl Fighter {
+    fn is_alive(&self) -> bool {
+        self.character.flags.contains(CharacterFlags::ALIVE)
+    }
+}
+
+/// Resolves a Fight: the player and everyone in the room who joins battles
+/// against every live monster in the room.
+pub fn fight(ctx: Context, world: &WorldHandle, client_id: u128) -> Outgoing {
+    let (room, players, monsters) = {
+        let world = world.lock();
+        let initiator = match world.player(client_id) {
+            Some(entity) => entity,
+            None => return reject(client_id, Error::NOT_READY, "You don't have a character in play."),
+        };
+        if !initiator.is_alive() {
+            return reject(client_id, Error::NO_FIGHT, "You can't fight while dead.");
+        }
+        let room = initiator.room();
+
+        let mut players = vec![];
+        let mut monsters = vec![];
+        for entity in world.in_room(room).filter(|entity| entity.is_alive()) {
+            let fighter = Fighter {
+                character: entity.character.clone(),
+                max_health: entity.max_health,
+            };
+            if entity.is_monster() {
+                monsters.push(fighter);
+            } else if entity.client_id == Some(client_id)
+                || entity.character.flags.contains(CharacterFlags::JOIN_BATTLE) {
+                players.push(fighter);
+            }
+        }
+
+        if monsters.is_empty() {
+            return reject(client_id, Error::NO_FIGHT, "There is nothing to fight here.");
+        }
+        (room, players, monsters)
+    };
+
+    resolve(ctx, world, room, players, monsters)
+}
+
+/// Resolves a PVPFight between the player and a named player in the same room.
+pub fn pvp_fight(ctx: Context, world: &WorldHandle, client_id: u128, target: &LurkName) -> Outgoing {
+    let (room, attacker, defender) = {
+        let world = world.lock();
+        if !world.combat.pvp {
+            return reject(client_id, Error::NO_PVP, "Player versus player combat is disabled.");
+        }
+        let attacker = match world.player(client_id) {
+            Some(entity) => entity,
+            None => return reject(client_id, Error::NOT_READY, "You don't have a character in play."),
+        };
+        if !attacker.is_alive() {
+            return reject(client_id, Error::NO_FIGHT, "You can't fight while dead.");
+        }
+        let defender = match world.get(target) {
+            Some(entity) if entity.room() == attacker.room() && entity.is_alive() && !entity.is_monster() => entity,
+            _ => return reject(client_id, Error::NO_TARGET, "That player isn't here to fight."),
+        };
+        (
+            attacker.room(),
+            Fighter { character: attacker.character.clone(), max_health: attacker.max_health },
+            Fighter { character: defender.character.clone(), max_health: defender.max_health },
+        )
+    };
+
+    resolve(ctx, world, room, vec![attacker], vec![defender])
+}
+
+/// Resolves a fight started by an aggressive monster against every living
+/// player in its room.
+pub fn ambush(ctx: Context, world: &WorldHandle, monster: &LurkName) -> Outgoing {
+    let (room, monsters, players) = {
+        let world = world.lock();
+        let entity = match world.get(monster) {
+            Some(entity) if entity.is_alive() => entity,
+            _ => return Outgoing::new(),
+        };
+        let room = entity.room();
+        let players: Vec<Fighter> = world.in_room(room)
+            .filter(|entity| entity.is_alive() && !entity.is_monster())
+            .map(|entity| Fighter { character: entity.character.clone(), max_health: entity.max_health })
+            .collect();
+        if players.is_empty() {
+            return Outgoing::new();
+        }
+        (room, vec![Fighter { character: entity.character.clone(), max_health: entity.max_health }], players)
+    };
+
+    resolve(ctx, world, room, monsters, players)
+}
+
+/// Runs the configured number of rounds, then writes health and flags back to the
+/// world and tells the room what happened. The world isn't locked while the Lua
+/// damage hook runs, so the hook is free to use the World API.
+fn resolve(ctx: Context, world: &WorldHandle, room: u16, mut attackers: Vec<Fighter>, mut defenders: Vec<Fighter>) -> Outgoing {
+    let (formula, rounds) = {
+        let world = world.lock();
+        (world.combat.formula, world.combat.rounds.max(1))
+    };
+
+    let mut narration = vec![];
+    for _ in 0..rounds {
+        strike_all(ctx, formula, &attackers, &mut defenders, &mut narration);
+        strike_all(ctx, formula, &defenders, &mut attackers, &mut narration);
+        for fighter in attackers.iter_mut().chain(defenders.iter_mut()).filter(|f| f.is_alive()) {
+            let healed = i32::from(fighter.character.health) + i32::from(fighter.character.regen);
+            fighter.character.health = healed.min(i32::from(fighter.max_health)) as i16;
+        }
+        if !attackers.iter().any(Fighter::is_alive) || !defenders.iter().any(Fighter::is_alive) {
+            break;
+        }
+    }
+
+    let mut out = Outgoing::new();
+    let mut world = world.lock();
+    let tick = world.tick;
+    for fighter in attackers.iter().chain(defenders.iter()) {
+        if let Some(entity) = world.get_mut(&fighter.character.name) {
+            entity.character.health = fighter.character.health;
+            entity.character.flags = fighter.character.flags;
+            entity.last_fought = Some(tick);
+        }
+    }
+    for fighter in attackers.iter().chain(defenders.iter()) {
+        if let Some(entity) = world.get(&fighter.character.name) {
+            world.broadcast(room, &LurkWriteMessage::Character(entity.character.clone()), &mut out);
+        }
+    }
+    for line in narration.iter() {
+        world.narrate(room, line, &mut out);
+    }
+    out
+}
+
+/// Each living fighter on one side hits the first living fighter on the other.
+fn strike_all(ctx: Context, formula: Formula, side: &[Fighter], other: &mut [Fighter], narration: &mut Vec<String>) {
+    for attacker in side.iter().filter(|f| f.is_alive()) {
+        let target = match other.iter_mut().find(|f| f.is_alive()) {
+            Some(target) => target,
+            None => return,
+        };
+
+        let damage = damage(ctx, formula, &attacker.character, &target.character);
+        target.character.health = target.character.health.saturating_sub(damage);
+
+        let attacker_name = attacker.character.name.to_string_lossy();
+        let target_name = target.character.name.to_string_lossy();
+        narration.push(format!("{} hits {} for {} damage.", attacker_name, target_name, damage));
+
+        if target.character.health <= 0 {
+            target.character.flags.remove(CharacterFlags::ALIVE);
+            narration.push(format!("{} has been slain by {}.", target_name, attacker_name));
+        }
+    }
+}
+
+/// A module can replace the formula by defining `on_combat_damage(attacker, defender)`.
+fn damage(ctx: Context, formula: Formula, attacker: &Character, defender: &Character) -> i16 {
+    if let Some(hook) = module::hook(ctx, "on_combat_damage") {
+        let result = hook.call::<_, i16>((attacker.clone(), defender.clone()));
+        match result {
+            Ok(damage) => return damage.max(0),
+            Err(e) => error!("Hook 'on_combat_damage' failed: {}", e),
+        }
+    }
+    formula.damage(attacker, defender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fighter(attack: u16, defense: u16) -> Character {
+        Character { attack, defense, ..Character::default() }
+    }
+
+    fn damage(formula: Formula, attack: u16, defense: u16) -> i16 {
+        formula.damage(&fighter(attack, 0), &fighter(0, defense))
+    }
+
+    #[test]
+    fn classic_subtracts_defense() {
+        assert_eq!(damage(Formula::Classic, 10, 3), 7);
+        assert_eq!(damage(Formula::Classic, 10, 0), 10);
+    }
+
+    #[test]
+    fn proportional_scales_by_share_of_attack() {
+        assert_eq!(damage(Formula::Proportional, 10, 10), 5);
+        assert_eq!(damage(Formula::Proportional, 30, 10), 22);
+        assert_eq!(damage(Formula::Proportional, 10, 0), 10);
+    }
+
+    #[test]
+    fn every_hit_does_at_least_a_point() {
+        for formula in [Formula::Classic, Formula::Proportional].iter() {
+            assert_eq!(damage(*formula, 3, 10), 1);
+            assert_eq!(damage(*formula, 0, 0), 1);
+            assert_eq!(damage(*formula, 0, u16::MAX), 1);
+        }
+    }
+
+    #[test]
+    fn damage_fits_in_health() {
+        assert_eq!(damage(Formula::Classic, u16::MAX, 0), i16::MAX);
+        assert_eq!(damage(Formula::Proportional, u16::MAX, 0), i16::MAX);
+        assert_eq!(damage(Formula::Proportional, u16::MAX, u16::MAX), i16::MAX);
+    }
+
+    #[test]
+    fn formulas_are_named() {
+        assert!(Formula::from_name("classic") == Some(Formula::Classic));
+        assert!(Formula::from_name("proportional") == Some(Formula::Proportional));
+        assert!(Formula::from_name("Classic").is_none());
+    }
+}
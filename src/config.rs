@@ -0,0 +1,389 @@
+use crate::cli::ServeArgs;
+use crate::client::SlowClient;
+use crate::log::{self, Filter, Format, Level};
+use crate::persist;
+use crate::protocol::Game;
+use crate::rate::{self, RateLimit};
+use crate::sandbox::Limits;
+use crate::store;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Everything the server runs with, read from a TOML file with `--config` and then
+/// overridden by any flags given on the command line. Every key is optional.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory containing the module's 'main.lua'.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    pub network: NetworkConfig,
+    pub limits: LimitsConfig,
+    pub server: ServerConfig,
+    pub lua: LuaConfig,
+    pub persistence: PersistenceConfig,
+    pub game: GameConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub listen: Vec<SocketAddr>,
+    /// Listeners whose clients skip the connection limits and access list.
+    pub trusted_listen: Vec<SocketAddr>,
+    pub ipv6_only: bool,
+    /// Seconds of silence before TCP keepalive probes start. Off when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepalive: Option<u64>,
+}
+
+/// Limits where zero means no limit.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_clients: usize,
+    pub max_clients_per_ip: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_list: Option<PathBuf>,
+    pub lobby_timeout: u64,
+    pub idle_timeout: u64,
+    pub idle_warning: u64,
+    pub max_queue_messages: usize,
+    pub max_queue_bytes: usize,
+    pub slow_client: SlowClient,
+    /// Laid over the default limits, so a file only needs the types it changes.
+    #[serde(deserialize_with = "over_default_limits")]
+    pub rate_limits: Vec<RateLimit>,
+    pub rate_strikes: u32,
+    /// Seconds over which rate limit strikes are forgiven, at least one. A client
+    /// over the limits is told so at most once in this long.
+    pub rate_window: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_clients: 1024,
+            max_clients_per_ip: 0,
+            access_list: None,
+            lobby_timeout: 120,
+            idle_timeout: 1800,
+            idle_warning: 60,
+            max_queue_messages: 1024,
+            max_queue_bytes: 1024 * 1024,
+            slow_client: SlowClient::Drop,
+            rate_limits: rate::DEFAULT_LIMITS.iter().map(|limit| limit.parse().unwrap()).collect(),
+            rate_strikes: 10,
+            rate_window: 60,
+        }
+    }
+}
+
+fn over_default_limits<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<RateLimit>, D::Error> {
+    let mut limits = LimitsConfig::default().rate_limits;
+    rate::merge(&mut limits, Vec::<RateLimit>::deserialize(deserializer)?);
+    Ok(limits)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub tick_ms: u64,
+    pub save_interval: u32,
+    pub shutdown_grace_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { tick_ms: 100, save_interval: 600, shutdown_grace_ms: 5000 }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LuaConfig {
+    pub max_instructions: u64,
+    pub max_memory_mb: usize,
+    pub watch: bool,
+}
+
+impl Default for LuaConfig {
+    fn default() -> Self {
+        LuaConfig { max_instructions: 10_000_000, max_memory_mb: 64, watch: false }
+    }
+}
+
+impl LuaConfig {
+    /// The sandbox limits the module runs under, where zero is no limit.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            instructions: Some(self.max_instructions).filter(|n| *n > 0),
+            memory: Some(self.max_memory_mb * 1024 * 1024).filter(|n| *n > 0),
+        }
+    }
+}
+
+/// Where saved state lives. Both default to inside the module directory.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub store: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saves: Option<PathBuf>,
+}
+
+/// What the server's `Game` message says, which modules get from `World:game()`.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub initial_points: u16,
+    pub stat_limit: u16,
+    pub description: String,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig { initial_points: 100, stat_limit: u16::MAX, description: String::new() }
+    }
+}
+
+impl GameConfig {
+    pub fn to_game(&self) -> Game {
+        Game {
+            initial_points: self.initial_points,
+            stat_limit: self.stat_limit,
+            description: self.description.as_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Level,
+    pub format: Format,
+    /// Levels for particular modules by path, e.g. `"lurk_world::net" = "debug"`,
+    /// overriding `level`. Module scripts log as `lua`.
+    pub filters: BTreeMap<String, Level>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig { level: Level::Info, format: Format::Text, filters: BTreeMap::new() }
+    }
+}
+
+impl LoggingConfig {
+    pub fn init(&self) {
+        let filters = self.filters.iter()
+            .map(|(target, level)| Filter { target: target.clone(), level: *level })
+            .collect();
+        log::init(self.level, filters, self.format);
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read '{}': {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("failed to parse '{}': {}", path.display(), e))
+    }
+
+    /// The file named by `--config`, if any, with the rest of the flags laid over it
+    /// and the paths left to default filled in.
+    pub fn from_args(args: &ServeArgs) -> Result<Config, String> {
+        let mut config = match &args.config {
+            Some(path) => Config::load(Path::new(path))?,
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.resolve()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, args: &ServeArgs) {
+        fn set<T: Clone>(field: &mut T, flag: &Option<T>) {
+            if let Some(value) = flag {
+                *field = value.clone();
+            }
+        }
+
+        if let Some(module) = &args.module {
+            self.module = Some(module.clone());
+        }
+
+        let network = &mut self.network;
+        if !args.listen.is_empty() {
+            network.listen = args.listen.clone();
+        } else if let Some(port) = args.port {
+            network.listen = vec![([0, 0, 0, 0], port).into()];
+        }
+        if !args.trusted_listen.is_empty() {
+            network.trusted_listen = args.trusted_listen.clone();
+        }
+        set(&mut network.ipv6_only, &args.ipv6_only());
+        if args.keepalive.is_some() {
+            network.keepalive = args.keepalive;
+        }
+
+        let limits = &mut self.limits;
+        set(&mut limits.max_clients, &args.max_clients);
+        set(&mut limits.max_clients_per_ip, &args.max_clients_per_ip);
+        if let Some(access_list) = &args.access_list {
+            limits.access_list = Some(PathBuf::from(access_list));
+        }
+        set(&mut limits.lobby_timeout, &args.lobby_timeout);
+        set(&mut limits.idle_timeout, &args.idle_timeout);
+        set(&mut limits.idle_warning, &args.idle_warning);
+        set(&mut limits.max_queue_messages, &args.max_queue_messages);
+        set(&mut limits.max_queue_bytes, &args.max_queue_bytes);
+        set(&mut limits.slow_client, &args.slow_client);
+        rate::merge(&mut limits.rate_limits, args.rate_limits.iter().cloned());
+        set(&mut limits.rate_strikes, &args.rate_strikes);
+        set(&mut limits.rate_window, &args.rate_window);
+
+        set(&mut self.server.tick_ms, &args.tick_ms);
+        set(&mut self.server.save_interval, &args.save_interval);
+        set(&mut self.server.shutdown_grace_ms, &args.shutdown_grace_ms);
+
+        set(&mut self.lua.max_instructions, &args.max_instructions);
+        set(&mut self.lua.max_memory_mb, &args.max_memory_mb);
+        set(&mut self.lua.watch, &args.watch());
+
+        if let Some(store) = &args.store {
+            self.persistence.store = Some(PathBuf::from(store));
+        }
+        if let Some(saves) = &args.saves {
+            self.persistence.saves = Some(PathBuf::from(saves));
+        }
+
+        set(&mut self.logging.level, &args.log_level);
+        set(&mut self.logging.format, &args.log_format);
+        for filter in args.log_filters.iter() {
+            self.logging.filters.insert(filter.target.clone(), filter.level);
+        }
+    }
+
+    fn resolve(&mut self) -> Result<(), String> {
+        let module = self.module.as_ref().map(PathBuf::from)
+            .ok_or_else(|| "no module given, set 'module' in the config or pass '--module'".to_string())?;
+        if self.network.listen.is_empty() && self.network.trusted_listen.is_empty() {
+            return Err("nothing to listen on, set 'network.listen' in the config or pass '--listen' or '--port'".to_string());
+        }
+        self.persistence.store.get_or_insert_with(|| module.join(store::STORE_FILE));
+        self.persistence.saves.get_or_insert_with(|| module.join(persist::SAVE_DIR));
+        Ok(())
+    }
+
+    /// The module directory. Only call on a config that came from `from_args`.
+    pub fn module(&self) -> &str {
+        self.module.as_deref().expect("config wasn't resolved")
+    }
+
+    pub fn store(&self) -> &Path {
+        self.persistence.store.as_deref().expect("config wasn't resolved")
+    }
+
+    pub fn saves(&self) -> &Path {
+        self.persistence.saves.as_deref().expect("config wasn't resolved")
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config can always be written as TOML")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Clap;
+
+    const FILE: &str = r#"
+        [network]
+        ipv6_only = true
+
+        [limits]
+        max_clients = 5
+        idle_timeout = 600
+        rate_limits = ["fight=1/1", "start=1/2"]
+        rate_window = 30
+
+        [lua]
+        watch = true
+    "#;
+
+    fn configured(flags: &[&str]) -> Config {
+        let mut config: Config = toml::from_str(FILE).unwrap();
+        let args = ServeArgs::parse_from(std::iter::once("lurk_world").chain(flags.iter().copied()));
+        config.apply(&args);
+        config
+    }
+
+    fn limit(config: &Config, event: &str) -> Vec<(f64, f64)> {
+        config.limits.rate_limits.iter()
+            .filter(|limit| limit.event == event)
+            .map(|limit| (limit.per_second, limit.burst))
+            .collect()
+    }
+
+    #[test]
+    fn file_limits_are_laid_over_the_defaults() {
+        let config = configured(&[]);
+        assert_eq!(limit(&config, "fight"), vec![(1.0, 1.0)]);
+        assert_eq!(limit(&config, "start"), vec![(1.0, 2.0)]);
+        assert_eq!(limit(&config, "message"), vec![(4.0, 8.0)]);
+        assert_eq!(limit(&config, "loot"), vec![(2.0, 4.0)]);
+        assert_eq!((config.limits.rate_strikes, config.limits.rate_window), (10, 30));
+    }
+
+    #[test]
+    fn flags_are_laid_over_the_file() {
+        let config = configured(&[
+            "--max-clients", "7", "--rate-limit", "fight=3/6", "--rate-limit", "leave=1", "--rate-window", "15",
+        ]);
+        assert_eq!(config.limits.max_clients, 7);
+        assert_eq!(config.limits.rate_window, 15);
+        assert_eq!(config.limits.idle_timeout, 600);
+        assert_eq!(limit(&config, "fight"), vec![(3.0, 6.0)]);
+        assert_eq!(limit(&config, "start"), vec![(1.0, 2.0)]);
+        assert_eq!(limit(&config, "leave"), vec![(1.0, 1.0)]);
+        assert_eq!(limit(&config, "message"), vec![(4.0, 8.0)]);
+    }
+
+    #[test]
+    fn switches_left_out_keep_the_file() {
+        let config = configured(&[]);
+        assert!(config.network.ipv6_only);
+        assert!(config.lua.watch);
+    }
+
+    #[test]
+    fn switches_given_override_the_file() {
+        let config = configured(&["--no-ipv6-only", "--no-watch"]);
+        assert!(!config.network.ipv6_only);
+        assert!(!config.lua.watch);
+
+        let config = configured(&["--no-watch", "--watch"]);
+        assert!(config.lua.watch);
+    }
+
+    #[test]
+    fn zero_lua_limits_are_no_limit() {
+        let limits = LuaConfig { max_instructions: 0, max_memory_mb: 2, watch: false }.limits();
+        assert_eq!((limits.instructions, limits.memory), (None, Some(2 * 1024 * 1024)));
+        let limits = LuaConfig { max_instructions: 5, max_memory_mb: 0, watch: false }.limits();
+        assert_eq!((limits.instructions, limits.memory), (Some(5), None));
+    }
+
+    #[test]
+    fn written_config_reads_back_the_same() {
+        let config = configured(&["--rate-limit", "fight=3/6"]);
+        let again: Config = toml::from_str(&config.to_toml()).unwrap();
+        assert_eq!(config.to_toml(), again.to_toml());
+    }
+}
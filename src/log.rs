@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How much a record matters, most important first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Level, String> {
+        match name {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("expected one of error, warn, info, debug or trace, not '{}'", name)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One human-readable line per record.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected 'text' or 'json', not '{}'", name)),
+        }
+    }
+}
+
+/// A level for the records of one module and the modules inside it, written
+/// `target=level`, e.g. `lurk_world::net=debug`. Module scripts log as `lua`.
+#[derive(Clone)]
+pub struct Filter {
+    pub target: String,
+    pub level: Level,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Filter, String> {
+        match text.find('=') {
+            Some(equals) => Ok(Filter {
+                target: text[..equals].to_string(),
+                level: text[equals + 1..].parse()?,
+            }),
+            None => Err(format!("expected 'target=level', not '{}'", text)),
+        }
+    }
+}
+
+/// Where records go and which of them are kept.
+struct Logger {
+    level: Level,
+    /// Longest target first, so the most specific filter wins.
+    filters: Vec<Filter>,
+    format: Format,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets up logging for the rest of the run. Until it's called, records at `info`
+/// and above are written as text.
+pub fn init(level: Level, mut filters: Vec<Filter>, format: Format) {
+    filters.sort_by_key(|filter| std::cmp::Reverse(filter.target.len()));
+    let _ = LOGGER.set(Logger { level, filters, format });
+}
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger { level: Level::Info, filters: vec![], format: Format::Text })
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    let logger = logger();
+    let threshold = logger.filters.iter()
+        .find(|filter| {
+            target == filter.target
+                || (target.starts_with(&filter.target) && target[filter.target.len()..].starts_with("::"))
+        })
+        .map_or(logger.level, |filter| filter.level);
+    level <= threshold
+}
+
+/// Writes a record to stderr. Fields are extra context, such as the client a
+/// record is about, kept apart from the message so they can be searched on.
+pub fn emit(level: Level, target: &str, fields: &[(&str, String)], message: fmt::Arguments) {
+    let time = timestamp(SystemTime::now());
+    let line = render(logger().format, &time, level, target, fields, &message.to_string());
+    let stderr = io::stderr();
+    let _ = writeln!(stderr.lock(), "{}", line);
+}
+
+/// A record as one line. Whatever a client or module put in the message or fields
+/// is escaped, so it can't break the line or pass for a record of its own.
+fn render(format: Format, time: &str, level: Level, target: &str, fields: &[(&str, String)], message: &str) -> String {
+    let mut line = String::new();
+    match format {
+        Format::Text => {
+            let _ = write!(line, "{} {:>5} {}: ", time, level.name().to_uppercase(), target);
+            escape(&mut line, message);
+            for (key, value) in fields {
+                let _ = write!(line, " {}=", key);
+                // Quoted when it could otherwise be taken for more than one field.
+                if !value.is_empty() && value.chars().all(|c| c.is_alphanumeric() || "-_.:/[]@".contains(c)) {
+                    line.push_str(value);
+                } else {
+                    json_string(&mut line, value);
+                }
+            }
+        }
+        Format::Json => {
+            line.push_str("{\"time\":");
+            json_string(&mut line, time);
+            line.push_str(",\"level\":");
+            json_string(&mut line, level.name());
+            line.push_str(",\"target\":");
+            json_string(&mut line, target);
+            line.push_str(",\"message\":");
+            json_string(&mut line, message);
+            for (key, value) in fields {
+                line.push(',');
+                json_string(&mut line, key);
+                line.push(':');
+                json_string(&mut line, value);
+            }
+            line.push('}');
+        }
+    }
+    line
+}
+
+fn json_string(out: &mut String, text: &str) {
+    out.push('"');
+    escape(out, text);
+    out.push('"');
+}
+
+/// Escapes quotes, backslashes and control characters as JSON does.
+fn escape(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// RFC 3339 in UTC, to the millisecond.
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let of_day = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, of_day / 3600, of_day / 60 % 60, of_day % 60, since.subsec_millis())
+}
+
+/// The Gregorian date a number of days after 1970-01-01 falls on.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The macros are in scope for every module declared after this one in main.rs.
+
+/// Logs at a level, with optional `key = value` fields before a `;` and then the
+/// message as for `format!`.
+macro_rules! log_at {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::emit($level, module_path!(), &[$((stringify!($key), $value.to_string())),+],
+                              format_args!($($arg)+));
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::emit($level, module_path!(), &[], format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: &str = "2020-01-02T03:04:05.006Z";
+
+    #[test]
+    fn text_records_stay_on_one_line() {
+        let fields = [("client", "7".to_string()), ("name", "Bob\nINFO forged".to_string())];
+        let line = render(Format::Text, TIME, Level::Info, "lua", &fields, "said \"hi\"\r\n\u{1b}[2J");
+        assert_eq!(line, format!(
+            "{}  INFO lua: said \\\"hi\\\"\\r\\n\\u001b[2J client=7 name=\"Bob\\nINFO forged\"", TIME));
+    }
+
+    #[test]
+    fn text_fields_are_quoted_unless_plain() {
+        let fields = [
+            ("peer", "[::1]:5000".to_string()),
+            ("name", "two words".to_string()),
+            ("other", "a=b".to_string()),
+            ("empty", String::new()),
+        ];
+        let line = render(Format::Text, TIME, Level::Warn, "lurk_world::net", &fields, "x");
+        assert!(line.ends_with(" x peer=[::1]:5000 name=\"two words\" other=\"a=b\" empty=\"\""), "{}", line);
+    }
+
+    #[test]
+    fn json_records_escape_control_characters() {
+        let fields = [("name", "a\"b\u{7f}".to_string())];
+        let line = render(Format::Json, TIME, Level::Error, "lua", &fields, "tab\tline\n");
+        assert_eq!(line, format!(
+            "{{\"time\":\"{}\",\"level\":\"error\",\"target\":\"lua\",\"message\":\"tab\\tline\\n\",\"name\":\"a\\\"b\\u007f\"}}",
+            TIME));
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(951_782_400_123);
+        assert_eq!(timestamp(time), "2000-02-29T00:00:00.123Z");
+    }
+}
@@ -0,0 +1,223 @@
+use crate::module;
+use crate::protocol::{Error, LurkName};
+use crate::world::{reject, Outgoing, WorldHandle};
+use crate::write::LurkWriteMessage;
+use rlua::{Context, Value};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GoldRule {
+    /// The looter takes everything.
+    All,
+    /// The looter takes this percentage, rounded down.
+    Percentage(u8),
+    /// The looter takes everything, but players can't be looted.
+    MonstersOnly,
+}
+
+#[derive(Clone)]
+pub struct LootRules {
+    pub gold: GoldRule,
+}
+
+impl Default for LootRules {
+    fn default() -> Self {
+        LootRules { gold: GoldRule::All }
+    }
+}
+
+impl GoldRule {
+    pub fn from_name(name: &str, percentage: Option<u8>) -> Option<GoldRule> {
+        match name {
+            "all" => Some(GoldRule::All),
+            "percentage" => Some(GoldRule::Percentage(percentage.unwrap_or(100).min(100))),
+            "monsters" => Some(GoldRule::MonstersOnly),
+            _ => None,
+        }
+    }
+
+    fn amount(self, gold: u16) -> u16 {
+        match self {
+            GoldRule::All | GoldRule::MonstersOnly => gold,
+            GoldRule::Percentage(percentage) => (u32::from(gold) * u32::from(percentage) / 100) as u16,
+        }
+    }
+}
+
+/// Resolves a Loot: moves gold from a dead character in the player's room to the player.
+pub fn loot(ctx: Context, world: &WorldHandle, client_id: u128, target: &LurkName) -> Outgoing {
+    let (looter, victim, amount) = {
+        let world = world.lock();
+        let looter = match world.player(client_id) {
+            Some(entity) if entity.is_alive() => entity,
+            Some(_) => return reject(client_id, Error::NOT_READY, "The dead can't loot."),
+            None => return reject(client_id, Error::NOT_READY, "You don't have a character in play."),
+        };
+        let victim = match world.get(target) {
+            Some(entity) if entity.room() == looter.room() => entity,
+            _ => return reject(client_id, Error::NO_TARGET, "There's nobody by that name here."),
+        };
+        if victim.is_alive() {
+            return reject(client_id, Error::BAD_MONSTER, "You can't loot the living.");
+        }
+        if world.loot.gold == GoldRule::MonstersOnly && !victim.is_monster() {
+            return reject(client_id, Error::BAD_MONSTER, "Only monsters can be looted.");
+        }
+        let amount = world.loot.gold.amount(victim.character.gold);
+        (looter.character.clone(), victim.character.clone(), amount)
+    };
+
+    // The module gets the final say: `on_loot(looter, target, amount)` may return
+    // false to refuse or a number to change how much gold moves.
+    let amount = match module::hook(ctx, "on_loot") {
+        Some(hook) => {
+            let result = hook.call::<_, Value>((looter.clone(), victim.clone(), amount));
+            match result {
+                Ok(Value::Boolean(false)) => return reject(client_id, Error::OTHER, "You can't loot that."),
+                Ok(Value::Integer(changed)) => changed.max(0).min(i64::from(victim.gold)) as u16,
+                Ok(Value::Number(changed)) => changed.max(0.0).min(f64::from(victim.gold)) as u16,
+                Ok(_) => amount,
+                Err(e) => {
+                    error!("Hook 'on_loot' failed: {}", e);
+                    return reject(client_id, Error::OTHER, "You can't loot that.");
+                }
+            }
+        }
+        None => amount,
+    };
+
+    let mut out = Outgoing::new();
+    let mut world = world.lock();
+    // Only as much as the looter can carry leaves the victim, so no gold is lost.
+    let capacity = match world.get(&looter.name) {
+        Some(entity) => u16::MAX - entity.character.gold,
+        None => return reject(client_id, Error::NOT_READY, "You don't have a character in play."),
+    };
+    let amount = match world.get_mut(&victim.name) {
+        Some(entity) => {
+            let amount = amount.min(entity.character.gold).min(capacity);
+            entity.character.gold -= amount;
+            amount
+        }
+        None => return reject(client_id, Error::NO_TARGET, "There's nobody by that name here."),
+    };
+    if let Some(entity) = world.get_mut(&looter.name) {
+        entity.character.gold += amount;
+    }
+
+    for name in [looter.name, victim.name].iter() {
+        if let Some(entity) = world.get(name) {
+            world.broadcast(entity.room(), &LurkWriteMessage::Character(entity.character.clone()), &mut out);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Character, CharacterFlags};
+    use rlua::Lua;
+
+    const LOOTER: u128 = 1;
+
+    fn character(name: &str, gold: u16, alive: bool) -> Character {
+        let mut character = Character {
+            name: LurkName::new(name),
+            gold,
+            current_room_number: 1,
+            health: 10,
+            ..Character::default()
+        };
+        character.flags.set(CharacterFlags::ALIVE, alive);
+        character
+    }
+
+    /// A living Hero with `gold` and a dead Rat with `loot` in the same room.
+    fn scene(rule: GoldRule, gold: u16, loot: u16) -> WorldHandle {
+        let world = WorldHandle::default();
+        {
+            let mut world = world.lock();
+            world.loot.gold = rule;
+            assert!(world.add_player(LOOTER, character("Hero", gold, true)).is_ok());
+            assert!(world.add_monster(character("Rat", loot, false)).is_ok());
+        }
+        world
+    }
+
+    /// Has the Hero loot `target`, with `module` defining the module's hooks.
+    fn loot_with(world: &WorldHandle, module: &str, target: &str) -> Outgoing {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.load(module).exec().unwrap();
+            loot(ctx, world, LOOTER, &LurkName::new(target))
+        })
+    }
+
+    fn gold(world: &WorldHandle, name: &str) -> u16 {
+        world.lock().get(&LurkName::new(name)).unwrap().character.gold
+    }
+
+    fn rejected(out: &Outgoing) -> Option<u8> {
+        match out.as_slice() {
+            [(LOOTER, LurkWriteMessage::Error(error))] => Some(error.code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn all_takes_everything() {
+        let world = scene(GoldRule::All, 10, 40);
+        let out = loot_with(&world, "", "Rat");
+        assert_eq!(rejected(&out), None);
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (50, 0));
+        assert!(out.iter().all(|(id, lurkmsg)| *id == LOOTER && matches!(lurkmsg, LurkWriteMessage::Character(_))));
+    }
+
+    #[test]
+    fn percentage_takes_a_share_rounded_down() {
+        let world = scene(GoldRule::Percentage(50), 0, 41);
+        loot_with(&world, "", "Rat");
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (20, 21));
+    }
+
+    #[test]
+    fn monsters_only_leaves_players_alone() {
+        let world = scene(GoldRule::MonstersOnly, 0, 40);
+        assert!(world.lock().add_player(2, character("Fallen", 30, false)).is_ok());
+        assert_eq!(rejected(&loot_with(&world, "", "Fallen")), Some(Error::BAD_MONSTER));
+        assert_eq!(gold(&world, "Fallen"), 30);
+
+        loot_with(&world, "", "Rat");
+        assert_eq!(gold(&world, "Hero"), 40);
+    }
+
+    #[test]
+    fn on_loot_can_refuse() {
+        let world = scene(GoldRule::All, 10, 40);
+        let out = loot_with(&world, "function on_loot(looter, target, amount) return false end", "Rat");
+        assert_eq!(rejected(&out), Some(Error::OTHER));
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (10, 40));
+    }
+
+    #[test]
+    fn on_loot_can_change_the_amount_within_the_victims_gold() {
+        let world = scene(GoldRule::All, 0, 40);
+        loot_with(&world, "function on_loot(looter, target, amount) return amount - 35 end", "Rat");
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (5, 35));
+
+        let world = scene(GoldRule::All, 0, 40);
+        loot_with(&world, "function on_loot() return 1000 end", "Rat");
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (40, 0));
+
+        let world = scene(GoldRule::All, 0, 40);
+        loot_with(&world, "function on_loot() return -3 end", "Rat");
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (0, 40));
+    }
+
+    #[test]
+    fn gold_the_looter_cant_carry_stays_with_the_victim() {
+        let world = scene(GoldRule::All, u16::MAX - 5, 40);
+        loot_with(&world, "", "Rat");
+        assert_eq!((gold(&world, "Hero"), gold(&world, "Rat")), (u16::MAX, 35));
+    }
+}
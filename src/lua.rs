@@ -1,12 +1,21 @@
 use std::sync::{Mutex, Arc};
 use std::collections::VecDeque;
 use crate::client::{ClientEvent, ClientEventKind};
-use rlua::{UserData, UserDataMethods, MetaMethod};
+use rlua::{Context, FromLua, ToLua, UserData, UserDataMethods, MetaMethod, Value};
 use crate::read::LurkReadEvent;
 use crate::write::LurkWriteMessage;
-use crate::protocol::{LurkName, Message, Error, Accept, Room, Character, Game, Connection, Version};
-use crate::protocol::CharacterFlags;
+use crate::protocol::{LurkName, Message, Error, Accept, Room, Character, Game, Connection};
+use crate::protocol::{CharacterFlags, TEXT_LENGTH};
 use rlua::prelude::LuaTable;
+use crate::world::{Outgoing, WorldHandle};
+use crate::combat::Formula;
+use crate::loot::GoldRule;
+use crate::monster::SpawnDefinition;
+use crate::store::{StoreHandle, StoredValue};
+use crate::log::{self, Level};
+use crate::session::{Disconnect, Session, Sessions};
+use std::sync::Weak;
+use std::time::UNIX_EPOCH;
 
 ///////////////////////////////////////////////////////////////////////////////
 
@@ -17,14 +26,204 @@ impl UserData for LurkName {
         });
 
         methods.add_method("string", |ctx, this, ()| {
-            let string = String::from_utf8_lossy(&this.bytes);
-            Ok(string.to_string())
+            ctx.create_string(this.as_bytes())
         });
     }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// A protocol type whose fields are readable and writable from Lua by name.
+/// Derived with `lurk_macros::LuaFields`.
+pub trait LuaFields: Sized {
+    fn type_name() -> &'static str;
+
+    /// `None` when the type has no field by that name.
+    fn get_field<'lua>(&self, ctx: Context<'lua>, key: &str) -> rlua::Result<Option<Value<'lua>>>;
+
+    /// `false` when the type has no field by that name.
+    fn set_field<'lua>(&mut self, ctx: Context<'lua>, key: &str, value: Value<'lua>) -> rlua::Result<bool>;
+}
+
+/// How a single field of a `LuaFields` type crosses into and out of Lua.
+pub trait LuaField: Sized {
+    fn to_lua_field<'lua>(&self, ctx: Context<'lua>) -> rlua::Result<Value<'lua>>;
+    fn from_lua_field<'lua>(value: Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self>;
+}
+
+macro_rules! number_field {
+    ($($number:ty),*) => {
+        $(
+            impl LuaField for $number {
+                fn to_lua_field<'lua>(&self, ctx: Context<'lua>) -> rlua::Result<Value<'lua>> {
+                    self.to_lua(ctx)
+                }
+
+                fn from_lua_field<'lua>(value: Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self> {
+                    <$number>::from_lua(value, ctx)
+                }
+            }
+        )*
+    };
+}
+
+number_field!(u8, u16, i16);
+
+/// Text goes to Lua as a byte string, untouched, since clients needn't send UTF-8.
+impl LuaField for Vec<u8> {
+    fn to_lua_field<'lua>(&self, ctx: Context<'lua>) -> rlua::Result<Value<'lua>> {
+        Ok(Value::String(ctx.create_string(self)?))
+    }
+
+    fn from_lua_field<'lua>(value: Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self> {
+        let text = rlua::String::from_lua(value, ctx)?;
+        if text.as_bytes().len() > TEXT_LENGTH {
+            return Err(rlua::Error::RuntimeError(format!(
+                "text is {} bytes, but at most {} fit", text.as_bytes().len(), TEXT_LENGTH
+            )));
+        }
+        Ok(text.as_bytes().to_vec())
+    }
+}
+
+/// Names go to Lua as byte strings without their null padding, and come back
+/// exactly as given or not at all.
+impl LuaField for LurkName {
+    fn to_lua_field<'lua>(&self, ctx: Context<'lua>) -> rlua::Result<Value<'lua>> {
+        Ok(Value::String(ctx.create_string(self.as_bytes())?))
+    }
+
+    fn from_lua_field<'lua>(value: Value<'lua>, ctx: Context<'lua>) -> rlua::Result<Self> {
+        if let Value::UserData(ud) = &value {
+            return Ok(*ud.borrow::<LurkName>()?);
+        }
+        let name = rlua::String::from_lua(value, ctx)?;
+        LurkName::from_bytes(name.as_bytes())
+            .map_err(|e| rlua::Error::RuntimeError(format!("bad name {:?}: {}", name.to_str().unwrap_or("<binary>"), e)))
+    }
+}
+
+/// Each flag is its own boolean field on the character.
+impl LuaFields for CharacterFlags {
+    fn type_name() -> &'static str {
+        "CharacterFlags"
+    }
+
+    fn get_field<'lua>(&self, _: Context<'lua>, key: &str) -> rlua::Result<Option<Value<'lua>>> {
+        Ok(character_flag(key).map(|flag| Value::Boolean(self.contains(flag))))
+    }
+
+    fn set_field<'lua>(&mut self, ctx: Context<'lua>, key: &str, value: Value<'lua>) -> rlua::Result<bool> {
+        match character_flag(key) {
+            Some(flag) => {
+                self.set(flag, bool::from_lua(value, ctx)?);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn character_flag(key: &str) -> Option<CharacterFlags> {
+    match key {
+        "alive" => Some(CharacterFlags::ALIVE),
+        "join_battle" => Some(CharacterFlags::JOIN_BATTLE),
+        "monster" => Some(CharacterFlags::MONSTER),
+        "started" => Some(CharacterFlags::STARTED),
+        "ready" => Some(CharacterFlags::READY),
+        _ => None,
+    }
+}
+
+fn no_field<T: LuaFields>(key: &str) -> rlua::Error {
+    rlua::Error::RuntimeError(format!("{} has no field '{}'", T::type_name(), key))
+}
+
+/// Reads and writes go through `LuaFields`, so a misspelt field is an error
+/// rather than a silent nil.
+fn add_field_methods<'lua, T, M>(methods: &mut M)
+where
+    T: LuaFields + UserData,
+    M: UserDataMethods<'lua, T>,
+{
+    methods.add_meta_method(MetaMethod::Index, |ctx, this, key: String| {
+        this.get_field(ctx, &key)?.ok_or_else(|| no_field::<T>(&key))
+    });
+
+    methods.add_meta_method_mut(MetaMethod::NewIndex, |ctx, this, (key, value): (String, Value)| {
+        if this.set_field(ctx, &key, value)? {
+            Ok(())
+        } else {
+            Err(no_field::<T>(&key))
+        }
+    });
+}
+
+macro_rules! protocol_userdata {
+    ($($protocol:ty),*) => {
+        $(
+            impl UserData for $protocol {
+                fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+                    add_field_methods(methods);
+                }
+            }
+        )*
+    };
+}
+
+protocol_userdata!(Message, Room, Character, Game, Connection);
+
+/// Takes a protocol value from Lua as its userdata or as a table of its fields,
+/// with missing fields left at their defaults. Anything else, nil included, is an
+/// error.
+pub fn protocol_from_lua<'lua, T>(ctx: Context<'lua>, value: Value<'lua>) -> rlua::Result<T>
+where
+    T: LuaFields + UserData + Clone + Default + 'static,
+{
+    match value {
+        Value::UserData(ud) => Ok(ud.borrow::<T>()?.clone()),
+        Value::Table(table) => {
+            let mut protocol = T::default();
+            for pair in table.pairs::<String, Value>() {
+                let (key, value) = pair?;
+                if !protocol.set_field(ctx, &key, value)? {
+                    return Err(no_field::<T>(&key));
+                }
+            }
+            Ok(protocol)
+        }
+        other => Err(rlua::Error::FromLuaConversionError {
+            from: other.type_name(),
+            to: T::type_name(),
+            message: None,
+        }),
+    }
+}
+
+fn install_constructor<'lua, T>(ctx: Context<'lua>) -> rlua::Result<()>
+where
+    T: LuaFields + UserData + Clone + Default + Send + 'static,
+{
+    let class = ctx.create_table()?;
+    // Only the constructor takes nothing to mean every field at its default.
+    class.set("new", ctx.create_function(|ctx, value: Value| match value {
+        Value::Nil => Ok(T::default()),
+        value => protocol_from_lua::<T>(ctx, value),
+    })?)?;
+    ctx.globals().set(T::type_name(), class)
+}
+
+/// Adds a global per protocol type with a `new` constructor, as in `Character.new{ name = "Bob" }`.
+pub fn install_protocol_types(ctx: Context) -> rlua::Result<()> {
+    install_constructor::<Message>(ctx)?;
+    install_constructor::<Room>(ctx)?;
+    install_constructor::<Character>(ctx)?;
+    install_constructor::<Game>(ctx)?;
+    install_constructor::<Connection>(ctx)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 #[derive(Clone)]
 pub struct ClientEventBuffer {
     events: Arc<Mutex<VecDeque<ClientEvent>>>,
@@ -55,304 +254,793 @@ impl ClientEventBuffer {
     }
 }
 
-impl UserData for ClientEventBuffer {
-    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        use rlua::String;
-        use std::string::String as StdString;
-        methods.add_method_mut("poll", |ctx, buffer, ()| {
-            let table = ctx.create_table()?;
-            if let Some(event) = buffer.pop() {
-                table.set("id", event.client_id()).unwrap();
-                table.set("isSome", true).unwrap();
-                match event.event() {
-                    ClientEventKind::Read(read_event) => {
-                        match read_event {
-                            LurkReadEvent::Message(msg) => {
-                                table.set("type", "message")?;
-                                table.set("recipient", msg.recipient)?;
-                                table.set("sender", msg.sender)?;
-                                let msg_string = StdString::from_utf8_lossy(&msg.message);
-                                table.set("message", msg_string.to_string())?;
-                            }
-                            LurkReadEvent::ChangeRoom(chgrm) => {
-                                table.set("type", "change_room")?;
-                                table.set("room_number", chgrm.room_number)?;
-                            }
-                            LurkReadEvent::Fight => {
-                                table.set("type", "fight")?;
-                            }
-                            LurkReadEvent::PVPFight(pvpfight) => {
-                                table.set("type", "pvp_fight")?;
-                                table.set("target", pvpfight.target)?;
-                            }
-                            LurkReadEvent::Loot(loot) => {
-                                table.set("type", "loot")?;
-                                table.set("type", loot.target)?;
-                            }
-                            LurkReadEvent::Start => {
-                                table.set("type", "start")?;
-                            }
-                            LurkReadEvent::Character(ch) => {
-                                table.set("type", "character")?;
-                                table.set("name", ch.name)?;
-
-                                // Flags
-                                table.set("alive", ch.flags.intersects(CharacterFlags::ALIVE))?;
-                                table.set("join_battle", ch.flags.intersects(CharacterFlags::JOIN_BATTLE))?;
-                                table.set("monster", ch.flags.intersects(CharacterFlags::MONSTER))?;
-                                table.set("started", ch.flags.intersects(CharacterFlags::STARTED))?;
-                                table.set("ready", ch.flags.intersects(CharacterFlags::READY))?;
-
-                                table.set("attack", ch.attack)?;
-                                table.set("defense", ch.defense)?;
-                                table.set("regen", ch.regen)?;
-                                table.set("health", ch.health)?;
-                                table.set("gold", ch.gold)?;
-                                table.set("room_number", ch.current_room_number)?;
-                                table.set("description", ch.description.clone())?;
-                            }
-                            LurkReadEvent::Leave => {
-                                table.set("type", "leave")?;
-                            }
-                            LurkReadEvent::Version(vers) => {
-                                table.set("type", "version")?;
-                                table.set("major", vers.major)?;
-                                table.set("minor", vers.minor)?;
-                            }
-                        }
-                    }
-                    ClientEventKind::Join => {
-                        table.set("type", "join")?;
-                    }
-                    ClientEventKind::Left => {
-                        table.set("type", "left")?;
-                    }
-                }
-            } else {
-                table.set("isSome", false).unwrap();
-            }
-            Ok(table)
-        });
-    }
-}
+/// Every event type a module can poll or listen for.
+pub const EVENT_TYPES: &[&str] = &[
+    "join", "left", "message", "change_room", "fight", "pvp_fight",
+    "loot", "start", "character", "leave", "version",
+];
 
-///////////////////////////////////////////////////////////////////////////////
+/// Registry keys for the listeners of the current Lua state. Keeping them in the
+/// state rather than the shared buffer means a reloaded module starts with none.
+const LISTENERS_KEY: &str = "lurk_listeners";
+const LISTENER_ID_KEY: &str = "lurk_listener_id";
 
-#[derive(Clone)]
-struct ClientWriteBuffer {
-    messages: Arc<Mutex<VecDeque<LurkWriteMessage>>>,
+pub fn event_type(event: &ClientEvent) -> &'static str {
+    match event.event() {
+        ClientEventKind::Read(read_event) => read_event.type_name(),
+        ClientEventKind::Join => "join",
+        ClientEventKind::Left(_) => "left",
+    }
 }
 
-impl Default for ClientWriteBuffer {
-    fn default() -> Self {
-        ClientWriteBuffer {
-            messages: Arc::new(Mutex::new(VecDeque::new())),
+fn event_table<'lua>(ctx: Context<'lua>, event: &ClientEvent) -> rlua::Result<LuaTable<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("id", event.client_id())?;
+    table.set("type", event_type(event))?;
+    if let ClientEventKind::Read(read_event) = event.event() {
+        match read_event {
+            LurkReadEvent::Message(msg) => {
+                table.set("message", msg.clone())?;
+            }
+            LurkReadEvent::ChangeRoom(chgrm) => {
+                table.set("room_number", chgrm.room_number)?;
+            }
+            LurkReadEvent::PVPFight(pvpfight) => {
+                table.set("target", pvpfight.target.to_lua_field(ctx)?)?;
+            }
+            LurkReadEvent::Loot(loot) => {
+                table.set("target", loot.target.to_lua_field(ctx)?)?;
+            }
+            LurkReadEvent::Character(ch) => {
+                table.set("character", ch.clone())?;
+            }
+            LurkReadEvent::Version(vers) => {
+                table.set("major", vers.major)?;
+                table.set("minor", vers.minor)?;
+            }
+            LurkReadEvent::Fight | LurkReadEvent::Start | LurkReadEvent::Leave => {}
         }
     }
+    if let ClientEventKind::Left(Some(kick)) = event.event() {
+        table.set("code", kick.code)?;
+        table.set("reason", kick.message.to_lua_field(ctx)?)?;
+    }
+    Ok(table)
 }
 
-impl ClientWriteBuffer {
-    fn add(&mut self, lurkmsg: LurkWriteMessage) {
-        if let Ok(mut lock) = self.messages.lock() {
-            lock.push_back(lurkmsg);
-        }
-        else {
-            eprintln!("Cannot queue message, write buffer is poisoned.");
+fn listeners(ctx: Context) -> rlua::Result<LuaTable> {
+    match ctx.named_registry_value::<_, Option<LuaTable>>(LISTENERS_KEY)? {
+        Some(listeners) => Ok(listeners),
+        None => {
+            let listeners = ctx.create_table()?;
+            ctx.set_named_registry_value(LISTENERS_KEY, listeners.clone())?;
+            Ok(listeners)
         }
     }
+}
 
-    pub fn add_message(&mut self, message: Message) {
-        self.add(LurkWriteMessage::Message(message));
+fn listeners_for<'lua>(ctx: Context<'lua>, kind: &str) -> rlua::Result<Vec<LuaTable<'lua>>> {
+    match listeners(ctx)?.get::<_, Option<LuaTable>>(kind)? {
+        Some(list) => list.sequence_values().collect(),
+        None => Ok(vec![]),
     }
+}
 
-    pub fn add_error(&mut self, error: Error) {
-        self.add(LurkWriteMessage::Error(error));
+/// Lists are replaced rather than edited, so a dispatch already walking the old
+/// one isn't disturbed by handlers that add or remove listeners.
+fn set_listeners_for<'lua>(ctx: Context<'lua>, kind: &str, entries: Vec<LuaTable<'lua>>) -> rlua::Result<()> {
+    listeners(ctx)?.set(kind, ctx.create_sequence_from(entries)?)
+}
+
+fn add_listener<'lua>(ctx: Context<'lua>, kind: &str, handler: rlua::Function<'lua>, priority: i64, once: bool) -> rlua::Result<i64> {
+    if !EVENT_TYPES.contains(&kind) {
+        return Err(rlua::Error::RuntimeError(format!("unknown event type '{}'", kind)));
     }
+    let id = ctx.named_registry_value::<_, Option<i64>>(LISTENER_ID_KEY)?.unwrap_or(0) + 1;
+    ctx.set_named_registry_value(LISTENER_ID_KEY, id)?;
+
+    let entry = ctx.create_table()?;
+    entry.set("handler", handler)?;
+    entry.set("priority", priority)?;
+    entry.set("once", once)?;
+    entry.set("id", id)?;
 
-    pub fn add_accept(&mut self, accept: Accept) {
-        self.add(LurkWriteMessage::Accept(accept));
+    // Higher priorities run first; equal ones run in the order they were added.
+    let mut entries = listeners_for(ctx, kind)?;
+    let mut position = entries.len();
+    for (i, existing) in entries.iter().enumerate() {
+        if existing.get::<_, i64>("priority")? < priority {
+            position = i;
+            break;
+        }
     }
+    entries.insert(position, entry);
+    set_listeners_for(ctx, kind, entries)?;
+    Ok(id)
+}
 
-    pub fn add_room(&mut self, room: Room) {
-        self.add(LurkWriteMessage::Room(room));
+/// Removes the listeners `keep` rejects, returning how many went.
+fn remove_listeners<'lua, F>(ctx: Context<'lua>, kind: &str, mut keep: F) -> rlua::Result<usize>
+where
+    F: FnMut(&LuaTable<'lua>) -> rlua::Result<bool>,
+{
+    let entries = listeners_for(ctx, kind)?;
+    let before = entries.len();
+    let mut kept = vec![];
+    for entry in entries {
+        if keep(&entry)? {
+            kept.push(entry);
+        }
+    }
+    let removed = before - kept.len();
+    if removed > 0 {
+        set_listeners_for(ctx, kind, kept)?;
+    }
+    Ok(removed)
+}
+
+/// Runs the module's listeners for an event, highest priority first, until one
+/// returns false. Returns whether there were any, in which case the event isn't
+/// also queued for `poll`. An error from a listener stops the dispatch.
+pub fn dispatch_event(ctx: Context, event: &ClientEvent) -> rlua::Result<bool> {
+    let kind = event_type(event);
+    let entries = listeners_for(ctx, kind)?;
+    if entries.is_empty() {
+        return Ok(false);
     }
 
-    pub fn add_character(&mut self, character: Character) {
-        self.add(LurkWriteMessage::Character(character));
+    let table = event_table(ctx, event)?;
+    for entry in entries {
+        if entry.get::<_, bool>("once")? {
+            let id: i64 = entry.get("id")?;
+            remove_listeners(ctx, kind, |other| Ok(other.get::<_, i64>("id")? != id))?;
+        }
+        let handler: rlua::Function = entry.get("handler")?;
+        if let Value::Boolean(false) = handler.call::<_, Value>(table.clone())? {
+            break;
+        }
     }
+    Ok(true)
+}
+
+impl UserData for ClientEventBuffer {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("poll", |ctx, buffer, ()| {
+            match buffer.pop() {
+                Some(event) => {
+                    let table = event_table(ctx, &event)?;
+                    table.set("isSome", true)?;
+                    Ok(table)
+                }
+                None => {
+                    let table = ctx.create_table()?;
+                    table.set("isSome", false)?;
+                    Ok(table)
+                }
+            }
+        });
+
+        methods.add_method("on", |ctx, _, (kind, handler, priority): (String, rlua::Function, Option<i64>)| {
+            add_listener(ctx, &kind, handler, priority.unwrap_or(0), false)
+        });
+
+        methods.add_method("once", |ctx, _, (kind, handler, priority): (String, rlua::Function, Option<i64>)| {
+            add_listener(ctx, &kind, handler, priority.unwrap_or(0), true)
+        });
 
-    pub fn add_game(&mut self, game: Game) {
-        self.add(LurkWriteMessage::Game(game));
+        // `off(id)` removes one listener, `off(type, handler)` every registration
+        // of that handler, `off(type)` all of the type's listeners and `off()`
+        // every listener there is.
+        methods.add_method("off", |ctx, _, (target, handler): (Value, Option<rlua::Function>)| {
+            let removed = match target {
+                Value::Nil => {
+                    let mut removed = 0;
+                    for kind in EVENT_TYPES {
+                        removed += remove_listeners(ctx, kind, |_| Ok(false))?;
+                    }
+                    removed
+                }
+                Value::Integer(id) => {
+                    let mut removed = 0;
+                    for kind in EVENT_TYPES {
+                        removed += remove_listeners(ctx, kind, |entry| Ok(entry.get::<_, i64>("id")? != id))?;
+                    }
+                    removed
+                }
+                Value::String(kind) => {
+                    let kind = kind.to_str()?;
+                    match handler {
+                        // Functions compare by identity as table keys.
+                        Some(handler) => {
+                            let probe = ctx.create_table()?;
+                            probe.raw_set(handler, true)?;
+                            remove_listeners(ctx, kind, |entry| {
+                                let other: rlua::Function = entry.get("handler")?;
+                                Ok(!probe.raw_get::<_, bool>(other)?)
+                            })?
+                        }
+                        None => remove_listeners(ctx, kind, |_| Ok(false))?,
+                    }
+                }
+                other => return Err(rlua::Error::RuntimeError(
+                    format!("off expects a listener id, an event type or nothing, got {}", other.type_name())
+                )),
+            };
+            Ok(removed > 0)
+        });
     }
+}
 
-    pub fn add_connection(&mut self, connection: Connection) {
-        self.add(LurkWriteMessage::Connection(connection));
+///////////////////////////////////////////////////////////////////////////////
+
+/// Protocol messages Lua has sent to clients, waiting for the server to deliver them.
+#[derive(Clone, Default)]
+pub struct ClientWriteBuffer {
+    messages: Arc<Mutex<Outgoing>>,
+}
+
+impl ClientWriteBuffer {
+    fn add(&self, client_id: u128, lurkmsg: LurkWriteMessage) {
+        self.messages.lock().unwrap().push((client_id, lurkmsg));
     }
 
-    pub fn add_version(&mut self, version: Version) {
-        self.add(LurkWriteMessage::Version(version));
+    /// Everything sent since the last call, in the order it was sent.
+    pub fn take(&self) -> Outgoing {
+        std::mem::take(&mut *self.messages.lock().unwrap())
     }
 }
 
-fn lossy_string_to_lurk_name(string: &str) -> LurkName {
-    let mut buf = [0u8; 32];
-    buf.copy_from_slice(&string.as_bytes()[0..32.min(string.len())]);
-    LurkName::from(buf)
+/// A protocol value a module can send as it is: a Message, Room, Character, Game or
+/// Connection made with its `new` constructor or taken from an event.
+fn protocol_message(value: Value) -> rlua::Result<LurkWriteMessage> {
+    if let Value::UserData(ud) = &value {
+        if let Ok(message) = ud.borrow::<Message>() {
+            return Ok(LurkWriteMessage::Message(message.clone()));
+        }
+        if let Ok(room) = ud.borrow::<Room>() {
+            return Ok(LurkWriteMessage::Room(room.clone()));
+        }
+        if let Ok(character) = ud.borrow::<Character>() {
+            return Ok(LurkWriteMessage::Character(character.clone()));
+        }
+        if let Ok(game) = ud.borrow::<Game>() {
+            return Ok(LurkWriteMessage::Game(game.clone()));
+        }
+        if let Ok(connection) = ud.borrow::<Connection>() {
+            return Ok(LurkWriteMessage::Connection(connection.clone()));
+        }
+    }
+    Err(rlua::Error::RuntimeError(format!(
+        "send expects a Message, Room, Character, Game or Connection, got {}", value.type_name())))
 }
 
-impl UserData for ClientWriteBuffer {
+///////////////////////////////////////////////////////////////////////////////
+
+impl UserData for WorldHandle {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method_mut("send_message", |_, buffer, table: LuaTable| {
-            let message: String = table.get("message")?;
-            let recipient = {
-                let data: String = table.get("recipient")?;
-                lossy_string_to_lurk_name(&data)
-            };
-            let sender = {
-                let data: String = table.get("sender")?;
-                lossy_string_to_lurk_name(&data)
-            };
+        methods.add_method("add_player", |ctx, world, (id, value): (u128, Value)| {
+            let character = protocol_from_lua(ctx, value)?;
+            world.lock().add_player(id, character)
+                .map_err(|e| rlua::Error::RuntimeError(String::from_utf8_lossy(&e.message).into_owned()))
+        });
 
-            let message = Message {
-                recipient,
-                sender,
-                message: message.into_bytes(),
-            };
-            buffer.add_message(message);
+        methods.add_method("add_monster", |ctx, world, value: Value| {
+            let character = protocol_from_lua(ctx, value)?;
+            world.lock().add_monster(character)
+                .map_err(|e| rlua::Error::RuntimeError(String::from_utf8_lossy(&e.message).into_owned()))
+        });
+
+        methods.add_method("remove", |ctx, world, name: Value| {
+            let name = LurkName::from_lua_field(name, ctx)?;
+            Ok(world.lock().remove(&name).is_some())
+        });
+
+        methods.add_method("get", |ctx, world, name: Value| {
+            let name = LurkName::from_lua_field(name, ctx)?;
+            Ok(world.lock().get(&name).map(|entity| entity.character.clone()))
+        });
+
+        // The configured Game message, ready for `send_game`.
+        methods.add_method("game", |_, world, ()| {
+            Ok(world.lock().game.clone())
+        });
+
+        methods.add_method("set_room", |ctx, world, (name, room_number): (Value, u16)| {
+            let name = LurkName::from_lua_field(name, ctx)?;
+            match world.lock().get_mut(&name) {
+                Some(entity) => {
+                    entity.character.current_room_number = room_number;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        });
+
+        methods.add_method("set_combat", |_, world, table: LuaTable| {
+            let mut world = world.lock();
+            if let Some(name) = table.get::<_, Option<String>>("formula")? {
+                world.combat.formula = Formula::from_name(&name)
+                    .ok_or_else(|| rlua::Error::RuntimeError(format!("unknown combat formula '{}'", name)))?;
+            }
+            if let Some(rounds) = table.get::<_, Option<u16>>("rounds")? {
+                world.combat.rounds = rounds;
+            }
+            if let Some(pvp) = table.get::<_, Option<bool>>("pvp")? {
+                world.combat.pvp = pvp;
+            }
             Ok(())
         });
 
-        methods.add_method_mut("send_message", |_, buffer, table: LuaTable| {
-            let code: u8 = table.get("code")?;
-            let message: String = table.get("message")?;
-            let error = Error {
-                code,
-                message: message.into_bytes(),
-            };
-            buffer.add_error(error);
+        methods.add_method("connect", |_, world, (from, to): (u16, u16)| {
+            world.lock().connect(from, to);
             Ok(())
         });
 
-        methods.add_method_mut("send_accept", |_, buffer, table: LuaTable| {
-            let code: u8 = table.get("action_type")?;
-            let accept = Accept {
-                code
+        methods.add_method("define_spawn", |ctx, world, table: LuaTable| {
+            let template: Character = protocol_from_lua(ctx, table.get("monster")?)?;
+            let definition = SpawnDefinition {
+                room: table.get::<_, Option<u16>>("room")?.unwrap_or(template.current_room_number),
+                respawn_ticks: table.get::<_, Option<u32>>("respawn")?.unwrap_or(300),
+                wander_chance: table.get::<_, Option<u8>>("wander")?.unwrap_or(0).min(100),
+                wander_ticks: table.get::<_, Option<u32>>("wander_every")?.unwrap_or(50),
+                aggressive: table.get::<_, Option<bool>>("aggressive")?.unwrap_or(false),
+                // Spawns could set their monster's regeneration before there was a
+                // world-wide interval, and still can.
+                regen_ticks: table.get::<_, Option<u32>>("regen_every")?,
+                template,
             };
-            buffer.add_accept(accept);
+            let mut world = world.lock();
+            let name = definition.template.name;
+            if matches!(world.get(&name), Some(entity) if !entity.is_monster()) {
+                return Err(rlua::Error::RuntimeError(format!(
+                    "can't define a spawn for '{}', a player goes by that name", name.to_string_lossy())));
+            }
+            world.monsters.define(definition);
             Ok(())
         });
 
-        methods.add_method_mut("send_room", |_, buffer, table: LuaTable| {
-            let room_number: u16 = table.get("number")?;
-            let room_name = {
-                let data: String = table.get("name")?;
-                lossy_string_to_lurk_name(&data)
-            };
-            let room_description: String = table.get("description")?;
-            let room = Room {
-                number: room_number,
-                name: room_name,
-                description: room_description.into_bytes(),
-            };
-            buffer.add_room(room);
+        methods.add_method("set_regen", |_, world, table: LuaTable| {
+            let mut world = world.lock();
+            if let Some(every) = table.get::<_, Option<u32>>("every")? {
+                world.regen.interval_ticks = every;
+            }
+            if let Some(skip) = table.get::<_, Option<bool>>("skip_in_combat")? {
+                world.regen.skip_in_combat = skip;
+            }
+            if let Some(cooldown) = table.get::<_, Option<u32>>("combat_cooldown")? {
+                world.regen.combat_cooldown_ticks = cooldown;
+            }
             Ok(())
         });
 
-        methods.add_method_mut("send_character", |_, buffer, table: LuaTable| {
-            let name = {
-                let data: String = table.get("name")?;
-                lossy_string_to_lurk_name(&data)
-            };
-            let flags = {
-                let mut flags = CharacterFlags::from(0u8);
-                if let Ok(is_alive) = table.get::<&str, bool>("alive")  {
-                    flags.set_alive(is_alive);
-                }
-                if let Ok(join_battle) = table.get::<&str, bool>("join_battle") {
-                    flags.set_join_battle(join_battle);
-                }
-                if let Ok(is_monster) = table.get::<&str, bool>("monster") {
-                    flags.set_monster(is_monster);
+        methods.add_method("set_max_health", |ctx, world, (name, max_health): (Value, i16)| {
+            let name = LurkName::from_lua_field(name, ctx)?;
+            match world.lock().get_mut(&name) {
+                Some(entity) => {
+                    entity.max_health = max_health;
+                    Ok(true)
                 }
-                if let Ok(is_started) = table.get::<&str, bool>("started") {
-                    flags.set_started(is_started);
-                }
-                if let Ok(is_ready) = table.get::<&str, bool>("ready") {
-                    flags.set_ready(is_ready);
-                }
-                flags
-            };
-            let attack: u16 = table.get("attack")?;
-            let defense: u16 = table.get("defense")?;
-            let regen: u16 = table.get("regen")?;
-            let health: i16 = table.get("health")?;
-            let gold: u16 = table.get("gold")?;
-            let current_room_number: u16 = table.get("room_number")?;
-            let description: String = table.get("description")?;
-            let character = Character {
-                name,
-                flags,
-                attack,
-                defense,
-                regen,
-                health,
-                gold,
-                current_room_number,
-                description: description.into_bytes(),
-            };
-            buffer.add_character(character);
+                None => Ok(false),
+            }
+        });
+
+        methods.add_method("set_loot", |_, world, table: LuaTable| {
+            let name: String = table.get("gold")?;
+            let percentage: Option<u8> = table.get("percentage")?;
+            world.lock().loot.gold = GoldRule::from_name(&name, percentage)
+                .ok_or_else(|| rlua::Error::RuntimeError(format!("unknown gold rule '{}'", name)))?;
             Ok(())
         });
-        
-        methods.add_method_mut("send_game", |_, buffer, table: LuaTable| {
-            let initial_points: u16 = table.get("initial_points")?;
-            let stat_limit: u16 = table.get("stat_limit")?;
-            let description: String = table.get("description")?;
-            let game = Game {
-                initial_points,
-                stat_limit,
-                description: description.into_bytes(),
-            };
-            buffer.add_game(game);
-           Ok(()) 
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+fn stored_to_lua<'lua>(ctx: Context<'lua>, value: &StoredValue) -> rlua::Result<rlua::Value<'lua>> {
+    Ok(match value {
+        StoredValue::String(bytes) => rlua::Value::String(ctx.create_string(bytes)?),
+        StoredValue::Integer(i) => rlua::Value::Integer(*i),
+        StoredValue::Number(n) => rlua::Value::Number(*n),
+        StoredValue::Boolean(b) => rlua::Value::Boolean(*b),
+    })
+}
+
+fn lua_to_stored(value: rlua::Value) -> rlua::Result<Option<StoredValue>> {
+    Ok(match value {
+        rlua::Value::Nil => None,
+        rlua::Value::String(s) => Some(StoredValue::String(s.as_bytes().to_vec())),
+        rlua::Value::Integer(i) => Some(StoredValue::Integer(i)),
+        rlua::Value::Number(n) => Some(StoredValue::Number(n)),
+        rlua::Value::Boolean(b) => Some(StoredValue::Boolean(b)),
+        other => return Err(rlua::Error::RuntimeError(
+            format!("can't store a {}, only strings, numbers and booleans", other.type_name())
+        )),
+    })
+}
+
+impl UserData for StoreHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |ctx, store, key: rlua::String| {
+            match store.lock().get(key.as_bytes()) {
+                Some(value) => stored_to_lua(ctx, value),
+                None => Ok(rlua::Value::Nil),
+            }
         });
-        
-        methods.add_method_mut("send_connection", |_, buffer, table: LuaTable| {
-            let room_number: u16 = table.get("number")?;
-            let room_name = {
-                let data: String = table.get("name")?;
-                lossy_string_to_lurk_name(&data)
-            };
-            let description: String = table.get("description")?;
-            let connection = Connection {
-                room_number,
-                room_name,
-                description: description.into_bytes(),
-            };
-            buffer.add_connection(connection);
+
+        methods.add_method("set", |_, store, (key, value): (rlua::String, rlua::Value)| {
+            let key = key.as_bytes().to_vec();
+            match lua_to_stored(value)? {
+                Some(value) => store.lock().set(key, value),
+                None => store.lock().delete(key),
+            }
+            Ok(())
+        });
+
+        methods.add_method("delete", |_, store, key: rlua::String| {
+            store.lock().delete(key.as_bytes().to_vec());
             Ok(())
         });
 
-        methods.add_method_mut("send_version", |_, buffer, table: LuaTable| {
-            let major: u8 = table.get("major")?;
-            let minor: u8 = table.get("minor")?;
-            let extensions: LuaTable = table.get("extensions")?;
-            let sequence = extensions.sequence_values::<String>();
-            
-            let mut loaded: Vec<Vec<u8>> = vec![];
-            
-            for entry in sequence {
-                let ext = entry?;
-                loaded.push(ext.into_bytes());
+        methods.add_method("scan", |ctx, store, prefix: Option<rlua::String>| {
+            let prefix = prefix.as_ref().map_or(&[][..], |p| p.as_bytes());
+            let table = ctx.create_table()?;
+            for (key, value) in store.lock().scan(prefix) {
+                table.set(ctx.create_string(&key)?, stored_to_lua(ctx, &value)?)?;
+            }
+            Ok(table)
+        });
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Registry key for each session's scratch table, by client id.
+const SCRATCH_KEY: &str = "lurk_session_scratch";
+
+/// A client's session as Lua sees it. It doesn't keep the session alive, so one
+/// held past its client's disconnect reads as closed.
+struct SessionView {
+    id: u128,
+    session: Weak<Mutex<Session>>,
+    world: WorldHandle,
+}
+
+fn scratch_tables(ctx: Context) -> rlua::Result<LuaTable> {
+    match ctx.named_registry_value::<_, Option<LuaTable>>(SCRATCH_KEY)? {
+        Some(tables) => Ok(tables),
+        None => {
+            let tables = ctx.create_table()?;
+            ctx.set_named_registry_value(SCRATCH_KEY, tables.clone())?;
+            Ok(tables)
+        }
+    }
+}
+
+/// Every session's scratch table, keyed by client id, for carrying across a reload.
+pub fn session_scratch(ctx: Context) -> rlua::Result<Value> {
+    Ok(Value::Table(scratch_tables(ctx)?))
+}
+
+pub fn restore_session_scratch<'lua>(ctx: Context<'lua>, tables: Value<'lua>) -> rlua::Result<()> {
+    ctx.set_named_registry_value(SCRATCH_KEY, tables)
+}
+
+/// Drops a departed client's scratch table.
+pub fn forget_session(ctx: Context, id: u128) -> rlua::Result<()> {
+    scratch_tables(ctx)?.set(id, Value::Nil)
+}
+
+impl UserData for SessionView {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Index, |ctx, view, key: String| {
+            if key == "id" {
+                return view.id.to_lua(ctx);
             }
-            
-            let version = Version {
-                major,
-                minor,
-                extensions: loaded,
+            let session = match view.session.upgrade() {
+                Some(session) => session,
+                None if key == "state" => return "closed".to_lua(ctx),
+                None => return Ok(Value::Nil),
             };
-            buffer.add_version(version);
-           Ok(())
+            let session = session.lock().unwrap();
+            match key.as_str() {
+                "state" => session.state.name().to_lua(ctx),
+                "peer" => session.peer.map(|peer| peer.to_string()).to_lua(ctx),
+                "trusted" => session.trusted.to_lua(ctx),
+                "connected_at" => session.connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0)
+                    .to_lua(ctx),
+                "queue" => {
+                    let queue = ctx.create_table()?;
+                    queue.set("messages", session.queue.messages())?;
+                    queue.set("bytes", session.queue.bytes())?;
+                    queue.set("dropped", session.queue.dropped())?;
+                    Ok(Value::Table(queue))
+                }
+                "character" => view.world.lock()
+                    .player(view.id)
+                    .map(|entity| entity.character.clone())
+                    .to_lua(ctx),
+                "scratch" => {
+                    let tables = scratch_tables(ctx)?;
+                    match tables.get::<_, Option<LuaTable>>(view.id)? {
+                        Some(scratch) => Ok(Value::Table(scratch)),
+                        None => {
+                            let scratch = ctx.create_table()?;
+                            tables.set(view.id, scratch.clone())?;
+                            Ok(Value::Table(scratch))
+                        }
+                    }
+                }
+                _ => Err(rlua::Error::RuntimeError(format!("Session has no field '{}'", key))),
+            }
         });
     }
 }
+
+/// Adds the `Clients` global: `Clients.get(id)` for one client's session, or nil
+/// if it's gone, and `Clients.list()` for every connected client's.
+///
+/// `Clients.kick(id, code, reason)` sends the client an Error and closes its
+/// connection; its 'left' event carries the code and reason. `Clients.poison(id)`
+/// drops it outright. Both return false if there's no such client.
+///
+/// `Clients.send(id, value)` queues a Message, Room, Character, Game or Connection
+/// for the client, `Clients.send_error(id, code, message)` an Error and
+/// `Clients.send_accept(id, code)` an Accept. They go into `outbox`, which the
+/// server delivers once the handler returns.
+pub fn install_clients(ctx: Context, sessions: &Sessions, world: &WorldHandle, outbox: &ClientWriteBuffer) -> rlua::Result<()> {
+    let clients = ctx.create_table()?;
+
+    let (get_sessions, get_world) = (sessions.clone(), world.clone());
+    clients.set("get", ctx.create_function(move |_, id: u128| {
+        Ok(get_sessions.get(id).map(|session| SessionView { id, session, world: get_world.clone() }))
+    })?)?;
+
+    let (list_sessions, list_world) = (sessions.clone(), world.clone());
+    clients.set("list", ctx.create_function(move |_, ()| {
+        let views: Vec<SessionView> = list_sessions.ids().into_iter()
+            .filter_map(|id| {
+                list_sessions.get(id).map(|session| SessionView { id, session, world: list_world.clone() })
+            })
+            .collect();
+        Ok(views)
+    })?)?;
+
+    let kick_sessions = sessions.clone();
+    clients.set("kick", ctx.create_function(move |ctx, (id, code, reason): (u128, u8, Value)| {
+        let reason = Vec::<u8>::from_lua_field(reason, ctx)?;
+        Ok(request_disconnect(&kick_sessions, id, Disconnect::Kick(Error { code, message: reason })))
+    })?)?;
+
+    let poison_sessions = sessions.clone();
+    clients.set("poison", ctx.create_function(move |_, id: u128| {
+        Ok(request_disconnect(&poison_sessions, id, Disconnect::Poison))
+    })?)?;
+
+    let send_outbox = outbox.clone();
+    clients.set("send", ctx.create_function(move |_, (id, value): (u128, Value)| {
+        send_outbox.add(id, protocol_message(value)?);
+        Ok(())
+    })?)?;
+
+    let error_outbox = outbox.clone();
+    clients.set("send_error", ctx.create_function(move |ctx, (id, code, message): (u128, u8, Value)| {
+        let message = Vec::<u8>::from_lua_field(message, ctx)?;
+        error_outbox.add(id, LurkWriteMessage::Error(Error { code, message }));
+        Ok(())
+    })?)?;
+
+    let accept_outbox = outbox.clone();
+    clients.set("send_accept", ctx.create_function(move |_, (id, code): (u128, u8)| {
+        accept_outbox.add(id, LurkWriteMessage::Accept(Accept { code }));
+        Ok(())
+    })?)?;
+
+    ctx.globals().set("Clients", clients)
+}
+
+/// False when there's no such client.
+fn request_disconnect(sessions: &Sessions, id: u128, disconnect: Disconnect) -> bool {
+    match sessions.get(id).and_then(|session| session.upgrade()) {
+        Some(session) => {
+            session.lock().unwrap().disconnect = Some(disconnect);
+            true
+        }
+        None => false,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Where records from module scripts are filed, for filtering.
+const LUA_LOG_TARGET: &str = "lua";
+
+/// Adds the `Log` global: `Log.error`, `Log.warn`, `Log.info` and `Log.debug` each
+/// take a message and an optional table of fields, and write a record to the
+/// server's log.
+pub fn install_log(ctx: Context) -> rlua::Result<()> {
+    let table = ctx.create_table()?;
+    for level in [Level::Error, Level::Warn, Level::Info, Level::Debug].iter().copied() {
+        table.set(level.name(), ctx.create_function(move |_, (message, fields): (rlua::String, Option<LuaTable>)| {
+            if !log::enabled(level, LUA_LOG_TARGET) {
+                return Ok(());
+            }
+            let mut pairs: Vec<(String, String)> = vec![];
+            if let Some(fields) = fields {
+                for pair in fields.pairs::<Value, Value>() {
+                    let (key, value) = pair?;
+                    pairs.push((log_text(&key), log_text(&value)));
+                }
+            }
+            let fields: Vec<(&str, String)> = pairs.iter()
+                .map(|(key, value)| (key.as_str(), value.clone()))
+                .collect();
+            let message = String::from_utf8_lossy(message.as_bytes());
+            log::emit(level, LUA_LOG_TARGET, &fields, format_args!("{}", message));
+            Ok(())
+        })?)?;
+    }
+    ctx.globals().set("Log", table)
+}
+
+fn log_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => String::from_utf8_lossy(text.as_bytes()).into_owned(),
+        Value::Integer(i) => i.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Nil => "nil".to_string(),
+        other => other.type_name().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    /// A bare Lua with the protocol types and `Clients`, sending into `outbox`.
+    fn clients_lua(outbox: &ClientWriteBuffer) -> Lua {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            install_protocol_types(ctx)?;
+            install_clients(ctx, &Sessions::default(), &WorldHandle::default(), outbox)
+        }).unwrap();
+        lua
+    }
+
+    #[test]
+    fn sent_values_are_queued_for_their_client() {
+        let outbox = ClientWriteBuffer::default();
+        let lua = clients_lua(&outbox);
+        lua.context(|ctx| {
+            ctx.load(r#"
+                Clients.send(7, Room.new{ number = 3, name = "Hall", description = "Dusty." })
+                Clients.send_error(7, 2, "nope")
+                Clients.send_accept(9, 6)
+            "#).exec()
+        }).unwrap();
+
+        let sent = outbox.take();
+        assert_eq!(sent.len(), 3);
+        match &sent[0] {
+            (7, LurkWriteMessage::Room(room)) => {
+                assert_eq!(room.number, 3);
+                assert!(room.name == LurkName::new("Hall"));
+                assert_eq!(room.description, b"Dusty.");
+            }
+            _ => panic!("expected a Room for client 7"),
+        }
+        match &sent[1] {
+            (7, LurkWriteMessage::Error(error)) => {
+                assert_eq!(error.code, 2);
+                assert_eq!(error.message, b"nope");
+            }
+            _ => panic!("expected an Error for client 7"),
+        }
+        assert!(matches!(&sent[2], (9, LurkWriteMessage::Accept(Accept { code: 6 }))));
+        assert!(outbox.take().is_empty());
+    }
+
+    #[test]
+    fn only_protocol_values_can_be_sent() {
+        let outbox = ClientWriteBuffer::default();
+        let lua = clients_lua(&outbox);
+        let result = lua.context(|ctx| ctx.load(r#"Clients.send(7, { number = 3 })"#).exec());
+        assert!(result.is_err());
+        assert!(outbox.take().is_empty());
+    }
+
+    /// A bare Lua with `Events`, after running `setup` in it.
+    fn events_lua(setup: &str) -> Lua {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            ctx.globals().set("Events", ClientEventBuffer::default())?;
+            ctx.load(setup).exec()
+        }).unwrap();
+        lua
+    }
+
+    /// Dispatches a Fight from client 7, returning whether anything listened.
+    fn fight(lua: &Lua) -> bool {
+        lua.context(|ctx| dispatch_event(ctx, &ClientEvent::read(7, LurkReadEvent::Fight))).unwrap()
+    }
+
+    fn calls(lua: &Lua) -> String {
+        lua.context(|ctx| ctx.globals().get("calls")).unwrap()
+    }
+
+    #[test]
+    fn listeners_run_highest_priority_first() {
+        let lua = events_lua(r#"
+            calls = ""
+            Events:on("fight", function(event) calls = calls .. "low" .. event.id end, -1)
+            Events:on("fight", function() calls = calls .. "first" end)
+            Events:on("fight", function() calls = calls .. "high" end, 10)
+            Events:on("fight", function() calls = calls .. "second" end)
+        "#);
+        assert!(fight(&lua));
+        assert_eq!(calls(&lua), "highfirstsecondlow7");
+    }
+
+    #[test]
+    fn returning_false_stops_later_listeners() {
+        let lua = events_lua(r#"
+            calls = ""
+            Events:on("fight", function() calls = calls .. "a" return false end, 1)
+            Events:on("fight", function() calls = calls .. "b" end)
+        "#);
+        assert!(fight(&lua));
+        assert!(fight(&lua));
+        assert_eq!(calls(&lua), "aa");
+    }
+
+    #[test]
+    fn once_listeners_run_once() {
+        let lua = events_lua(r#"
+            calls = ""
+            Events:once("fight", function() calls = calls .. "once" end)
+            Events:on("fight", function() calls = calls .. "on" end)
+        "#);
+        assert!(fight(&lua));
+        assert!(fight(&lua));
+        assert_eq!(calls(&lua), "onceonon");
+    }
+
+    #[test]
+    fn events_nobody_listens_for_are_left_to_poll() {
+        let lua = events_lua(r#"Events:on("message", function() end)"#);
+        assert!(!fight(&lua));
+    }
+
+    #[test]
+    fn off_removes_by_id_handler_type_or_everything() {
+        let lua = events_lua(r#"
+            calls = ""
+            local function a() calls = calls .. "a" end
+            local function b() calls = calls .. "b" end
+            local c = Events:on("fight", function() calls = calls .. "c" end)
+            Events:on("fight", a)
+            Events:on("fight", a)
+            Events:on("fight", b)
+            Events:on("leave", b)
+
+            assert(Events:off(c))
+            assert(not Events:off(c))
+            assert(Events:off("fight", a))
+            assert(not Events:off("fight", a))
+        "#);
+        assert!(fight(&lua));
+        assert_eq!(calls(&lua), "b");
+
+        lua.context(|ctx| ctx.load(r#"assert(Events:off("fight"))"#).exec()).unwrap();
+        assert!(!fight(&lua));
+        let leave = ClientEvent::read(7, LurkReadEvent::Leave);
+        assert!(lua.context(|ctx| dispatch_event(ctx, &leave)).unwrap());
+
+        lua.context(|ctx| ctx.load(r#"assert(Events:off()) assert(not Events:off())"#).exec()).unwrap();
+        assert!(!lua.context(|ctx| dispatch_event(ctx, &leave)).unwrap());
+    }
+}
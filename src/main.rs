@@ -3,23 +3,66 @@ extern crate clap;
 #[macro_use]
 extern crate bitflags;
 extern crate byteorder;
+extern crate libc;
 extern crate lurk_macros;
 extern crate rlua;
 
+#[macro_use]
+mod log;
+mod access;
 mod cli;
+mod config;
 mod client;
+mod combat;
+mod persist;
 mod protocol;
+mod rate;
+mod loot;
 mod lua;
+mod module;
+mod monster;
+mod net;
 mod read;
+mod regen;
+mod reload;
 mod read_buffer;
+mod sandbox;
 mod server;
+mod session;
+mod signal;
+mod store;
+mod validate;
+mod world;
 mod write;
 
 fn main() {
     use clap::Clap;
-    use cli::Args;
+    use cli::{Args, Command, ConfigCommand};
 
     let args: Args = Args::parse();
 
-    server::server(&args);
+    match args.command {
+        Command::Serve(serve_args) => {
+            let config = load_config(&serve_args);
+            config.logging.init();
+            server::server(&config);
+        }
+        Command::Validate(validate_args) => {
+            if !validate::validate(&validate_args) {
+                std::process::exit(1);
+            }
+        }
+        Command::Config(config_args) => match config_args.command {
+            ConfigCommand::Check(serve_args) => print!("{}", load_config(&serve_args).to_toml()),
+        },
+    }
+}
+
+/// The configuration the flags and any config file they name add up to, exiting
+/// with the reason if they don't add up to one the server can run with.
+fn load_config(args: &cli::ServeArgs) -> config::Config {
+    config::Config::from_args(args).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    })
 }
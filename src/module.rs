@@ -0,0 +1,246 @@
+use rlua::prelude::LuaTable;
+use rlua::{Context, Function, Value};
+use std::path::{Path, PathBuf};
+
+pub const MAIN_SCRIPT: &str = "main.lua";
+
+/// Registry keys for modules `require` has loaded and is loading.
+const LOADED_KEY: &str = "lurk_loaded";
+const LOADING_KEY: &str = "lurk_loading";
+
+/// Modules shipped with the server, required as `lurk.<name>`.
+const STD_LIBRARY: &[(&str, &str)] = &[
+    ("util", include_str!("../lua/lurk/util.lua")),
+];
+
+/// Hooks the server calls every module through. A module without one still runs,
+/// with a warning, since modules written before they existed leave them out.
+pub const EXPECTED_HOOKS: &[&str] = &["on_tick"];
+
+pub fn main_script_path(module: &str) -> PathBuf {
+    Path::new(module).join(MAIN_SCRIPT)
+}
+
+/// Runs the top level of 'main.lua', which is expected to define the module's hooks.
+pub fn load_main(ctx: Context, module: &str) -> Result<(), String> {
+    use std::fs::read_to_string;
+
+    let path = main_script_path(module);
+    let src = read_to_string(&path)
+        .map_err(|e| format!("failed to read '{}': {}", path.display(), e))?;
+
+    install_require(ctx, module).map_err(|e| e.to_string())?;
+
+    ctx.load(&src)
+        .set_name("main")
+        .and_then(|chunk| chunk.exec())
+        .map_err(|e| e.to_string())
+}
+
+/// Looks up a global hook function, returning `None` if the module doesn't define it.
+pub fn hook<'lua>(ctx: Context<'lua>, name: &str) -> Option<Function<'lua>> {
+    match ctx.globals().get::<_, Value>(name) {
+        Ok(Value::Function(f)) => Some(f),
+        _ => None,
+    }
+}
+
+/// Describes each expected hook the loaded module is missing or has defined as
+/// something other than a function.
+pub fn missing_hooks(ctx: Context) -> Vec<String> {
+    EXPECTED_HOOKS.iter()
+        .filter_map(|name| match ctx.globals().get::<_, Value>(*name) {
+            Ok(Value::Function(_)) => None,
+            Ok(Value::Nil) => Some(format!("hook '{}' is not defined", name)),
+            Ok(other) => Some(format!("hook '{}' must be a function, found {}", name, other.type_name())),
+            Err(e) => Some(e.to_string()),
+        })
+        .collect()
+}
+
+/// Replaces `require` with one that only loads from the module directory and the
+/// server's standard library. `require "a.b"` loads 'a/b.lua' or 'a/b/init.lua'.
+pub fn install_require(ctx: Context, module: &str) -> rlua::Result<()> {
+    let root = Path::new(module)
+        .canonicalize()
+        .map_err(|e| rlua::Error::RuntimeError(format!("module directory '{}': {}", module, e)))?;
+
+    ctx.set_named_registry_value(LOADED_KEY, ctx.create_table()?)?;
+    ctx.set_named_registry_value(LOADING_KEY, ctx.create_table()?)?;
+    let require = ctx.create_function(move |ctx, name: String| require(ctx, &root, &name))?;
+    ctx.globals().set("require", require)
+}
+
+fn require<'lua>(ctx: Context<'lua>, root: &Path, name: &str) -> rlua::Result<Value<'lua>> {
+    let loaded: LuaTable = ctx.named_registry_value(LOADED_KEY)?;
+    match loaded.get::<_, Value>(name)? {
+        Value::Nil => {}
+        cached => return Ok(cached),
+    }
+
+    let loading: LuaTable = ctx.named_registry_value(LOADING_KEY)?;
+    if loading.get::<_, bool>(name)? {
+        return Err(rlua::Error::RuntimeError(format!("module '{}' requires itself", name)));
+    }
+
+    let src = find_source(root, name)?;
+    loading.set(name, true)?;
+    let result = ctx.load(&src).set_name(name).and_then(|chunk| chunk.call::<_, Value>(name));
+    loading.set(name, Value::Nil)?;
+
+    // As with Lua's own require, a module that returns nothing is recorded as `true`.
+    let value = match result? {
+        Value::Nil => Value::Boolean(true),
+        value => value,
+    };
+    loaded.set(name, value.clone())?;
+    Ok(value)
+}
+
+fn find_source(root: &Path, name: &str) -> rlua::Result<String> {
+    let not_found = |reason: String| Err(rlua::Error::RuntimeError(format!("module '{}' not found: {}", name, reason)));
+
+    if name.starts_with("lurk.") {
+        return match STD_LIBRARY.iter().find(|(std_name, _)| *std_name == &name[5..]) {
+            Some((_, src)) => Ok(src.to_string()),
+            None => not_found("no such standard module".to_string()),
+        };
+    }
+
+    // Only plain dotted names, so nothing like '..' or '/etc' can reach the file system.
+    let segments: Vec<&str> = name.split('.').collect();
+    let valid = segments.iter().all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    });
+    if !valid {
+        return not_found("names are dot separated letters, digits, '_' and '-'".to_string());
+    }
+
+    let relative: PathBuf = segments.iter().collect();
+    let candidates = [
+        root.join(&relative).with_extension("lua"),
+        root.join(&relative).join("init.lua"),
+    ];
+    if let Some(candidate) = candidates.iter().find(|path| path.is_file()) {
+        // A symlink could still point outside the module.
+        let real = candidate.canonicalize()
+            .map_err(|e| rlua::Error::RuntimeError(format!("module '{}': {}", name, e)))?;
+        if !real.starts_with(root) {
+            return not_found(format!("'{}' is outside the module directory", candidate.display()));
+        }
+        return std::fs::read_to_string(&real)
+            .map_err(|e| rlua::Error::RuntimeError(format!("module '{}': {}", name, e)));
+    }
+    not_found(format!("no '{}' or '{}'", candidates[0].display(), candidates[1].display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+    use std::fs;
+
+    /// A module directory of its own for each test, holding `files`.
+    fn scratch(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurk_world_module_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, src) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, src).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs `src` with `require` installed for `dir`, returning what it evaluates to.
+    fn eval<T: for<'lua> rlua::FromLuaMulti<'lua>>(dir: &Path, src: &str) -> rlua::Result<T> {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            install_require(ctx, dir.to_str().unwrap())?;
+            ctx.load(src).eval()
+        })
+    }
+
+    /// An error with everything that caused it, since callback errors only show a traceback.
+    fn causes(error: &rlua::Error) -> String {
+        let mut text = error.to_string();
+        let mut source = std::error::Error::source(error);
+        while let Some(cause) = source {
+            text = format!("{}: {}", text, cause);
+            source = cause.source();
+        }
+        text
+    }
+
+    #[test]
+    fn dotted_names_resolve_under_the_root() {
+        let dir = scratch("dotted", &[
+            ("a/b.lua", "return 'file'"),
+            ("c/init.lua", "return 'init'"),
+        ]);
+        let root = dir.canonicalize().unwrap();
+        assert_eq!(find_source(&root, "a.b").unwrap(), "return 'file'");
+        assert_eq!(find_source(&root, "c").unwrap(), "return 'init'");
+        assert!(find_source(&root, "missing").is_err());
+    }
+
+    #[test]
+    fn paths_out_of_the_root_are_refused() {
+        let outside = scratch("outside", &[("secret.lua", "return 'secret'")]);
+        let dir = scratch("escape", &[("inner.lua", "return 'inner'")]);
+        let root = dir.canonicalize().unwrap();
+
+        for name in &["..", "..secret", "a..b", "../outside/secret", "/etc/passwd", ".inner", "inner."] {
+            assert!(find_source(&root, name).is_err(), "{:?} was allowed", name);
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(outside.join("secret.lua"), dir.join("link.lua")).unwrap();
+            assert!(find_source(&root, "link").is_err());
+        }
+    }
+
+    #[test]
+    fn lurk_names_are_the_standard_library() {
+        let dir = scratch("std", &[("lurk/util.lua", "return 'shadowed'")]);
+        let trimmed: String = eval(&dir, r#"return require("lurk.util").trim("  x  ")"#).unwrap();
+        assert_eq!(trimmed, "x");
+        assert!(eval::<()>(&dir, r#"require "lurk.missing""#).is_err());
+    }
+
+    #[test]
+    fn a_module_runs_once_and_is_cached() {
+        let dir = scratch("cached", &[("counter.lua", "runs = (runs or 0) + 1 return { runs = runs }")]);
+        let (same, runs): (bool, i64) = eval(&dir, r#"
+            local first = require "counter"
+            local second = require "counter"
+            return first == second, runs
+        "#).unwrap();
+        assert!(same);
+        assert_eq!(runs, 1);
+
+        let nothing: bool = eval(&scratch("nothing", &[("empty.lua", "")]), r#"return require "empty""#).unwrap();
+        assert!(nothing);
+    }
+
+    #[test]
+    fn a_require_cycle_is_an_error() {
+        let dir = scratch("cycle", &[
+            ("a.lua", "return require 'b'"),
+            ("b.lua", "return require 'a'"),
+        ]);
+        let error = eval::<()>(&dir, r#"require "a""#).err().unwrap();
+        assert!(causes(&error).contains("module 'a' requires itself"), "{}", causes(&error));
+
+        // Nothing is left half loaded, so a fixed module can be required again.
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            install_require(ctx, dir.to_str().unwrap()).unwrap();
+            assert!(ctx.load(r#"require "a""#).exec().is_err());
+            fs::write(dir.join("b.lua"), "return 'b'").unwrap();
+            assert_eq!(ctx.load(r#"return require "a""#).eval::<String>().unwrap(), "b");
+        });
+    }
+}
@@ -0,0 +1,366 @@
+use crate::combat;
+use crate::protocol::{Character, CharacterFlags, LurkName};
+use crate::world::{Outgoing, World, WorldHandle};
+use crate::write::LurkWriteMessage;
+use rlua::Context;
+use std::collections::HashMap;
+
+/// Where and how a monster appears. Tick counts are in server ticks.
+#[derive(Clone)]
+pub struct SpawnDefinition {
+    pub room: u16,
+    /// The monster as it spawns. Its name must be unique in the world.
+    pub template: Character,
+    pub respawn_ticks: u32,
+    /// Percent chance of moving to a connected room each time `wander_ticks` pass.
+    pub wander_chance: u8,
+    pub wander_ticks: u32,
+    /// Aggressive monsters start a fight with players who enter their room.
+    pub aggressive: bool,
+    /// Ticks between this monster's regeneration passes, in place of the world's.
+    pub regen_ticks: Option<u32>,
+}
+
+#[derive(Clone)]
+struct Spawn {
+    definition: SpawnDefinition,
+    respawn_in: Option<u32>,
+    /// Players already in the monster's room, so only new arrivals are attacked.
+    seen: Vec<LurkName>,
+}
+
+/// Small xorshift generator; monster behaviour doesn't need anything stronger.
+#[derive(Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn seeded() -> Rng {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Rng::from_seed(nanos)
+    }
+
+    /// Xorshift never leaves a zero state, so the seed is made odd.
+    fn from_seed(seed: u64) -> Rng {
+        Rng { state: seed | 1 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound.max(1)
+    }
+}
+
+#[derive(Clone)]
+pub struct Monsters {
+    spawns: Vec<Spawn>,
+    rng: Rng,
+}
+
+impl Default for Monsters {
+    fn default() -> Self {
+        Monsters {
+            spawns: vec![],
+            rng: Rng::seeded(),
+        }
+    }
+}
+
+impl Monsters {
+    /// Registers a spawn and puts its monster into the world straight away. Defining
+    /// the same monster again, as a reloaded module does, replaces its definition
+    /// and leaves the monster where it is.
+    pub fn define(&mut self, definition: SpawnDefinition) {
+        let name = definition.template.name;
+        if let Some(spawn) = self.spawns.iter_mut().find(|spawn| spawn.definition.template.name == name) {
+            spawn.definition = definition;
+            return;
+        }
+        self.spawns.push(Spawn {
+            definition,
+            respawn_in: Some(0),
+            seen: vec![],
+        });
+    }
+
+    /// Whether the monster by this name comes from a spawn.
+    pub fn spawns(&self, name: &LurkName) -> bool {
+        self.spawns.iter().any(|spawn| spawn.definition.template.name == *name)
+    }
+
+    /// The regeneration intervals of monsters spawned with their own.
+    pub fn regen_intervals(&self) -> HashMap<LurkName, u32> {
+        self.spawns.iter()
+            .filter_map(|spawn| Some((spawn.definition.template.name, spawn.definition.regen_ticks?)))
+            .collect()
+    }
+}
+
+/// Advances every spawn by one tick: respawns, wandering and aggression.
+pub fn tick(ctx: Context, world: &WorldHandle) -> Outgoing {
+    let mut out = Outgoing::new();
+    let mut ambushes = vec![];
+    {
+        let mut guard = world.lock();
+        let world = &mut *guard;
+        let mut monsters = std::mem::take(&mut world.monsters);
+
+        for spawn in monsters.spawns.iter_mut() {
+            let name = spawn.definition.template.name;
+            let alive = matches!(world.get(&name), Some(entity) if entity.is_alive());
+
+            if !alive {
+                // A player may have taken the name while the monster was gone. It
+                // comes back once the name is free again.
+                if matches!(world.get(&name), Some(entity) if !entity.is_monster()) {
+                    continue;
+                }
+                spawn.seen.clear();
+                let remaining = spawn.respawn_in.unwrap_or(spawn.definition.respawn_ticks);
+                if remaining > 0 {
+                    spawn.respawn_in = Some(remaining - 1);
+                    continue;
+                }
+                spawn.respawn_in = None;
+                let mut character = spawn.definition.template.clone();
+                character.flags.insert(CharacterFlags::ALIVE);
+                character.current_room_number = spawn.definition.room;
+                world.remove(&name);
+                if let Err(e) = world.add_monster(character) {
+                    error!("Failed to spawn monster '{}': {}", name.to_string_lossy(), String::from_utf8_lossy(&e.message));
+                    continue;
+                }
+                send_monster(world, &name, None, &mut out);
+                continue;
+            }
+
+            if world.every(spawn.definition.wander_ticks)
+                && monsters.rng.below(100) < u64::from(spawn.definition.wander_chance) {
+                wander(world, &mut monsters.rng, &name, &mut out);
+            }
+
+            if spawn.definition.aggressive {
+                let room = world.get(&name).map_or(spawn.definition.room, |entity| entity.room());
+                let present: Vec<LurkName> = world.in_room(room)
+                    .filter(|entity| !entity.is_monster() && entity.is_alive())
+                    .map(|entity| entity.character.name)
+                    .collect();
+                if present.iter().any(|player| !spawn.seen.contains(player)) {
+                    ambushes.push(name);
+                }
+                spawn.seen = present;
+            }
+        }
+
+        world.monsters = monsters;
+    }
+
+    for name in ambushes.iter() {
+        out.append(&mut combat::ambush(ctx, world, name));
+    }
+    out
+}
+
+fn wander(world: &mut World, rng: &mut Rng, name: &LurkName, out: &mut Outgoing) {
+    let from = match world.get(name) {
+        Some(entity) => entity.room(),
+        None => return,
+    };
+    let exits = world.connections(from);
+    if exits.is_empty() {
+        return;
+    }
+    let to = exits[rng.below(exits.len() as u64) as usize];
+    if let Some(entity) = world.get_mut(name) {
+        entity.character.current_room_number = to;
+    }
+    send_monster(world, name, Some(from), out);
+}
+
+/// Shows the monster to its room, and to the room it just left if it moved.
+fn send_monster(world: &World, name: &LurkName, left: Option<u16>, out: &mut Outgoing) {
+    if let Some(entity) = world.get(name) {
+        let lurkmsg = LurkWriteMessage::Character(entity.character.clone());
+        world.broadcast(entity.room(), &lurkmsg, out);
+        if let Some(room) = left {
+            world.broadcast(room, &lurkmsg, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rlua::Lua;
+
+    const PLAYER: u128 = 1;
+
+    fn rat(respawn_ticks: u32) -> SpawnDefinition {
+        SpawnDefinition {
+            room: 1,
+            template: Character {
+                name: LurkName::new("Rat"),
+                attack: 5,
+                health: 10,
+                ..Character::default()
+            },
+            respawn_ticks,
+            wander_chance: 0,
+            wander_ticks: 0,
+            aggressive: false,
+            regen_ticks: None,
+        }
+    }
+
+    fn player(name: &str, room: u16) -> Character {
+        let mut character = Character {
+            name: LurkName::new(name),
+            health: 100,
+            current_room_number: room,
+            ..Character::default()
+        };
+        character.flags.insert(CharacterFlags::ALIVE);
+        character
+    }
+
+    fn world_with(definition: SpawnDefinition) -> WorldHandle {
+        let world = WorldHandle::default();
+        {
+            let mut world = world.lock();
+            world.monsters = Monsters { spawns: vec![], rng: Rng::from_seed(7) };
+            world.monsters.define(definition);
+        }
+        world
+    }
+
+    /// Runs one server tick of the spawns, as the server loop does.
+    fn tick_once(lua: &Lua, world: &WorldHandle) -> Outgoing {
+        let out = lua.context(|ctx| tick(ctx, world));
+        world.lock().tick += 1;
+        out
+    }
+
+    fn rat_alive(world: &WorldHandle) -> bool {
+        matches!(world.lock().get(&LurkName::new("Rat")), Some(entity) if entity.is_alive() && entity.is_monster())
+    }
+
+    fn rat_room(world: &WorldHandle) -> u16 {
+        world.lock().get(&LurkName::new("Rat")).unwrap().room()
+    }
+
+    fn kill_rat(world: &WorldHandle) {
+        let mut world = world.lock();
+        let rat = world.get_mut(&LurkName::new("Rat")).unwrap();
+        rat.character.flags.remove(CharacterFlags::ALIVE);
+        rat.character.health = 0;
+    }
+
+    #[test]
+    fn monsters_spawn_at_once_and_respawn_after_their_delay() {
+        let (lua, world) = (Lua::new(), world_with(rat(3)));
+        tick_once(&lua, &world);
+        assert!(rat_alive(&world));
+        assert_eq!(rat_room(&world), 1);
+
+        kill_rat(&world);
+        for _ in 0..3 {
+            tick_once(&lua, &world);
+            assert!(!rat_alive(&world));
+        }
+        tick_once(&lua, &world);
+        assert!(rat_alive(&world));
+        assert_eq!(world.lock().get(&LurkName::new("Rat")).unwrap().character.health, 10);
+    }
+
+    #[test]
+    fn monsters_wander_through_exits_by_chance() {
+        let mut wanderer = rat(0);
+        wanderer.wander_ticks = 2;
+        wanderer.wander_chance = 100;
+        let (lua, world) = (Lua::new(), world_with(wanderer.clone()));
+        world.lock().connect(1, 2);
+        world.lock().connect(2, 1);
+
+        tick_once(&lua, &world);
+        assert_eq!(rat_room(&world), 1);
+        // Tick 1 isn't on the interval; tick 2 is.
+        tick_once(&lua, &world);
+        assert_eq!(rat_room(&world), 1);
+        tick_once(&lua, &world);
+        assert_eq!(rat_room(&world), 2);
+
+        wanderer.wander_chance = 0;
+        let world = world_with(wanderer.clone());
+        world.lock().connect(1, 2);
+        for _ in 0..10 {
+            tick_once(&lua, &world);
+            assert_eq!(rat_room(&world), 1);
+        }
+
+        // Seeded, so the same chances come up every run.
+        wanderer.wander_ticks = 1;
+        wanderer.wander_chance = 50;
+        let world = world_with(wanderer);
+        world.lock().connect(1, 2);
+        world.lock().connect(2, 1);
+        tick_once(&lua, &world);
+        let mut moves = 0;
+        for _ in 0..100 {
+            let before = rat_room(&world);
+            tick_once(&lua, &world);
+            if rat_room(&world) != before {
+                moves += 1;
+            }
+        }
+        assert!(moves > 20 && moves < 80, "{} moves", moves);
+    }
+
+    #[test]
+    fn aggressive_monsters_ambush_players_who_arrive() {
+        let mut biter = rat(0);
+        biter.aggressive = true;
+        let (lua, world) = (Lua::new(), world_with(biter));
+        tick_once(&lua, &world);
+
+        assert!(world.lock().add_player(PLAYER, player("Hero", 1)).is_ok());
+        let out = tick_once(&lua, &world);
+        assert!(!out.is_empty());
+        let fought = |world: &WorldHandle| world.lock().get(&LurkName::new("Hero")).unwrap().last_fought;
+        assert_eq!(fought(&world), Some(1));
+
+        // Only new arrivals are attacked.
+        tick_once(&lua, &world);
+        assert_eq!(fought(&world), Some(1));
+    }
+
+    #[test]
+    fn a_spawn_never_takes_a_players_name() {
+        let (lua, world) = (Lua::new(), world_with(rat(0)));
+        tick_once(&lua, &world);
+        world.lock().remove(&LurkName::new("Rat"));
+        assert!(world.lock().add_player(PLAYER, player("Rat", 1)).is_ok());
+
+        for _ in 0..3 {
+            tick_once(&lua, &world);
+            let world = world.lock();
+            let entity = world.get(&LurkName::new("Rat")).unwrap();
+            assert_eq!(entity.client_id, Some(PLAYER));
+            assert!(!entity.is_monster());
+        }
+
+        world.lock().remove_client(PLAYER);
+        tick_once(&lua, &world);
+        assert!(rat_alive(&world));
+    }
+}
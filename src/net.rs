@@ -0,0 +1,210 @@
+use crate::client::{Client, ClientEvent};
+use crate::rate::{Rate, RateLimits, RateState};
+use crate::read::LurkReadEvent;
+use crate::write::LurkWriteMessage;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[cfg(not(feature = "tokio"))]
+mod threads;
+#[cfg(not(feature = "tokio"))]
+pub use self::threads::{Connection, Network};
+
+#[cfg(feature = "tokio")]
+mod codec;
+#[cfg(feature = "tokio")]
+mod tasks;
+#[cfg(feature = "tokio")]
+pub use self::tasks::{Connection, Network};
+
+/// How long a closing connection waits for the peer to hang up after its last
+/// messages are written.
+const CLOSE_LINGER: Duration = Duration::from_millis(500);
+
+/// How long a listener waits after failing to accept, so errors that persist,
+/// such as running out of file descriptors, don't spin it.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// How the network treats the connections it accepts.
+#[derive(Clone, Default)]
+pub struct NetConfig {
+    /// How long a connection may sit silent before TCP keepalive probes start.
+    pub keepalive: Option<Duration>,
+    /// How fast a connection may send each type of event. Checked as events are
+    /// decoded, so a flood never reaches the game thread.
+    pub rates: RateLimits,
+}
+
+impl NetConfig {
+    /// Applies the socket options to a freshly accepted connection.
+    #[cfg(unix)]
+    fn configure<S: std::os::unix::io::AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        if let Some(idle) = self.keepalive {
+            let fd = socket.as_raw_fd();
+            set_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            let secs = idle.as_secs().max(1).min(i32::MAX as u64) as libc::c_int;
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs)?;
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            set_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, secs)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn configure<S>(&self, _: &S) -> io::Result<()> {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+        if self.keepalive.is_some() {
+            WARNED.call_once(|| warn!("TCP keepalive isn't supported on this platform, ignoring it."));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// A socket the server accepts clients on. Clients of a trusted listener skip the
+/// connection limits and access list.
+pub struct Listener {
+    pub socket: TcpListener,
+    pub trusted: bool,
+}
+
+impl Listener {
+    /// Binds to the address. An IPv6 listener takes IPv4 clients too unless
+    /// `v6_only` is set.
+    pub fn bind(address: SocketAddr, v6_only: bool, trusted: bool) -> io::Result<Listener> {
+        let socket = match address {
+            SocketAddr::V6(v6) => bind_v6(v6, v6_only)?,
+            SocketAddr::V4(_) => TcpListener::bind(address)?,
+        };
+        Ok(Listener { socket, trusted })
+    }
+}
+
+#[cfg(unix)]
+fn bind_v6(address: std::net::SocketAddrV6, v6_only: bool) -> io::Result<TcpListener> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owning the descriptor straight away closes it if anything below fails.
+    let socket = unsafe { TcpListener::from_raw_fd(fd) };
+    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, v6_only as libc::c_int)?;
+
+    let mut raw: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    raw.sin6_port = address.port().to_be();
+    raw.sin6_flowinfo = address.flowinfo();
+    raw.sin6_addr.s6_addr = address.ip().octets();
+    raw.sin6_scope_id = address.scope_id();
+    let bound = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &raw as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if bound != 0 || unsafe { libc::listen(socket.as_raw_fd(), 128) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Without a way to set the socket option, the platform decides whether an IPv6
+/// listener takes IPv4 clients.
+#[cfg(not(unix))]
+fn bind_v6(address: std::net::SocketAddrV6, _: bool) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+}
+
+/// What the network side hands the game thread.
+pub enum Inbound {
+    Joined(Client),
+    Event(ClientEvent),
+    /// The client went over the rate limit on a type of event, which was dropped.
+    /// Sent at most once a strike window, so the client is told to slow down.
+    Throttled(u128, &'static str),
+    /// The client went over the rate limit once too often, and should be kicked.
+    /// Nothing more it sends is passed on.
+    Flooding(u128, &'static str),
+    /// The client's connection closed or broke the protocol.
+    Closed(u128),
+}
+
+impl Inbound {
+    /// What the game thread hears of an event a client sent, if anything.
+    fn screen(id: u128, event: LurkReadEvent, rate: &mut RateState, limits: &RateLimits) -> Option<Inbound> {
+        let kind = event.type_name();
+        match rate.check(kind, limits) {
+            Rate::Within => Some(Inbound::Event(ClientEvent::read(id, event))),
+            Rate::Over => Some(Inbound::Throttled(id, kind)),
+            Rate::Muted => None,
+            Rate::Abusive => Some(Inbound::Flooding(id, kind)),
+        }
+    }
+}
+
+/// What the game thread asks of a client's connection.
+pub enum Outbound {
+    Message(LurkWriteMessage),
+    /// Write what's queued, then shut the connection gracefully.
+    Close,
+}
+
+/// Messages sent to a connection that its writer hasn't handed to the socket yet,
+/// and how many Character updates were superseded because the client wasn't
+/// keeping up. Exposed to modules through each session's `queue`; the server has
+/// no metrics exporter to report it to.
+#[derive(Default)]
+pub struct QueueDepth {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl QueueDepth {
+    pub fn messages(&self) -> usize {
+        self.messages.load(Ordering::SeqCst)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn push(&self, lurkmsg: &LurkWriteMessage) {
+        self.messages.fetch_add(1, Ordering::SeqCst);
+        self.bytes.fetch_add(lurkmsg.wire_size(), Ordering::SeqCst);
+    }
+
+    fn pop(&self, lurkmsg: &LurkWriteMessage) {
+        self.messages.fetch_sub(1, Ordering::SeqCst);
+        self.bytes.fetch_sub(lurkmsg.wire_size(), Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::client::CLIENT_BUFFER_LIMIT;
+use crate::read::{self, LurkReadEvent};
+use crate::write::{LurkWrite, LurkWriteMessage};
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Frames LURK for the async backend with the same decoder and writer the
+/// threaded one uses.
+#[derive(Default)]
+pub struct LurkCodec;
+
+impl Decoder for LurkCodec {
+    type Item = LurkReadEvent;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<LurkReadEvent>> {
+        if src.len() > CLIENT_BUFFER_LIMIT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "client overran its buffer"));
+        }
+        // Decoded in place; only the bytes of a whole message are taken off.
+        let mut rest: &[u8] = src;
+        let event = read::decode(&mut rest)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a LURK message"))?;
+        let used = src.len() - rest.len();
+        src.advance(used);
+        Ok(event)
+    }
+}
+
+impl Encoder<LurkWriteMessage> for LurkCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, lurkmsg: LurkWriteMessage, dst: &mut BytesMut) -> io::Result<()> {
+        dst.writer().write_lurk_message(&lurkmsg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ChangeRoom, Error};
+
+    #[test]
+    fn only_whole_messages_are_taken() {
+        let mut codec = LurkCodec;
+        // The start of a ChangeRoom to room 5.
+        let mut src = BytesMut::from(&[2u8, 5][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(&src[..], &[2, 5]);
+
+        // The rest of it, then a Fight.
+        src.extend_from_slice(&[0, 3]);
+        let event = codec.decode(&mut src).unwrap();
+        assert!(matches!(event, Some(LurkReadEvent::ChangeRoom(ChangeRoom { room_number: 5 }))));
+        assert_eq!(&src[..], &[3]);
+        assert!(matches!(codec.decode(&mut src).unwrap(), Some(LurkReadEvent::Fight)));
+        assert!(src.is_empty());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn bytes_that_arent_lurk_are_an_error() {
+        let mut src = BytesMut::from(&[0xffu8][..]);
+        assert!(LurkCodec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn an_overrun_buffer_is_an_error() {
+        let mut src = BytesMut::from(&vec![1u8; CLIENT_BUFFER_LIMIT + 1][..]);
+        assert!(LurkCodec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn messages_are_written_whole() {
+        let mut dst = BytesMut::new();
+        let error = Error::new(Error::OTHER, "Nope.");
+        LurkCodec.encode(LurkWriteMessage::Error(error), &mut dst).unwrap();
+        assert_eq!(&dst[..], &[7, 0, 5, 0, b'N', b'o', b'p', b'e', b'.']);
+    }
+}
@@ -0,0 +1,227 @@
+use super::codec::LurkCodec;
+use super::{Inbound, Listener, NetConfig, Outbound, QueueDepth, ACCEPT_BACKOFF, CLOSE_LINGER};
+use crate::client::ClientFactory;
+use crate::rate::{RateLimits, RateState};
+use crate::protocol::Error;
+use crate::write::LurkWriteMessage;
+use futures_util::{SinkExt, StreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
+
+/// The game thread's end of one client's connection.
+pub struct Connection {
+    outbox: UnboundedSender<Outbound>,
+    cut: CancellationToken,
+    queue: Arc<QueueDepth>,
+}
+
+impl Connection {
+    /// False once the connection's writer has stopped.
+    pub fn send(&self, outbound: Outbound) -> bool {
+        if let Outbound::Message(lurkmsg) = &outbound {
+            self.queue.push(lurkmsg);
+        }
+        self.outbox.send(outbound).is_ok()
+    }
+
+    pub fn queue(&self) -> &Arc<QueueDepth> {
+        &self.queue
+    }
+
+    /// Closes the socket on the spot, dropping anything still queued.
+    pub fn cut(&self) {
+        self.cut.cancel();
+    }
+}
+
+/// Socket I/O on a tokio runtime, kept off the game thread. Each connection gets a
+/// task decoding its events and one writing its messages, so neither a slow client
+/// nor a slow module holds up the other, and thousands of clients share a handful
+/// of threads.
+pub struct Network {
+    inbound: Receiver<Inbound>,
+    stop: CancellationToken,
+    connections: Arc<AtomicUsize>,
+    _runtime: Runtime,
+}
+
+impl Network {
+    pub fn start(listeners: Vec<Listener>, factory: ClientFactory, config: NetConfig) -> Network {
+        let runtime = Runtime::new().expect("Failed to start the network runtime.");
+        let (inbound, receiver) = mpsc::channel();
+        let stop = CancellationToken::new();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let factory = Arc::new(Mutex::new(factory));
+        for listener in listeners {
+            runtime.spawn(accept(listener, factory.clone(), config.clone(), inbound.clone(), stop.clone(),
+                                 connections.clone()));
+        }
+        Network { inbound: receiver, stop, connections, _runtime: runtime }
+    }
+
+    /// The next thing that happened on the network, if anything has.
+    pub fn poll(&self) -> Option<Inbound> {
+        self.inbound.try_recv().ok()
+    }
+
+    /// Turns away any further connections.
+    pub fn stop_accepting(&self) {
+        self.stop.cancel();
+    }
+
+    /// Connections whose writer is still running.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+async fn accept(listener: Listener, factory: Arc<Mutex<ClientFactory>>, config: NetConfig,
+                inbound: Sender<Inbound>, stop: CancellationToken, connections: Arc<AtomicUsize>) {
+    let Listener { socket, trusted } = listener;
+    let listener = match socket.set_nonblocking(true).and_then(|_| TcpListener::from_std(socket)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen for connections: {}", e);
+            return;
+        }
+    };
+    loop {
+        let stream = tokio::select! {
+            _ = stop.cancelled() => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::select! {
+                        _ = stop.cancelled() => return,
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                    }
+                }
+            },
+        };
+        if let Err(e) = config.configure(&stream) {
+            warn!("Failed to accept a connection: {}", e);
+            continue;
+        }
+        connect(stream, trusted, &factory, &config, &inbound, &connections);
+    }
+}
+
+fn connect(stream: TcpStream, trusted: bool, factory: &Mutex<ClientFactory>, config: &NetConfig,
+           inbound: &Sender<Inbound>, connections: &Arc<AtomicUsize>) {
+    let peer = stream.peer_addr().ok();
+    // Held from the admission check until the client exists, so listeners admitting
+    // at once can't both take the last place.
+    let mut factory = factory.lock().unwrap();
+    if let Err(error) = factory.admit(peer, trusted) {
+        tokio::spawn(reject(stream, error));
+        return;
+    }
+
+    let (outbox, outgoing) = unbounded_channel();
+    let cut = CancellationToken::new();
+    let queue = Arc::new(QueueDepth::default());
+    let connection = Connection { outbox, cut: cut.clone(), queue: queue.clone() };
+    let client = factory.create(peer, trusted, connection);
+    drop(factory);
+    let id = client.id();
+    let address = peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+    info!(client = id, peer = address, trusted = trusted; "Client connected.");
+    if inbound.send(Inbound::Joined(client)).is_err() {
+        return;
+    }
+
+    let (read_half, write_half) = stream.into_split();
+    // Cancelled when the reader finishes, which a closing writer waits on.
+    let read_done = CancellationToken::new();
+    let reader = FramedRead::new(read_half, LurkCodec);
+    tokio::spawn(read(reader, id, inbound.clone(), config.rates.clone(), cut.clone(), read_done.clone()));
+
+    connections.fetch_add(1, Ordering::SeqCst);
+    let connections = connections.clone();
+    let writer = FramedWrite::new(write_half, LurkCodec);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cut.cancelled() => {}
+            _ = write(writer, outgoing, &queue, read_done) => {}
+        }
+        cut.cancel();
+        connections.fetch_sub(1, Ordering::SeqCst);
+    });
+}
+
+/// Tells a connection that wasn't admitted why, then hangs up on it.
+async fn reject(stream: TcpStream, error: Error) {
+    let mut frames = FramedWrite::new(stream, LurkCodec);
+    if frames.send(LurkWriteMessage::Error(error)).await.is_ok() {
+        let _ = frames.close().await;
+    }
+}
+
+/// Decodes events until the connection closes, sends something that isn't LURK,
+/// overruns its buffer or is cut. Events over the rate limits are dropped here.
+async fn read(mut frames: FramedRead<OwnedReadHalf, LurkCodec>, id: u128, events: Sender<Inbound>,
+              rates: RateLimits, cut: CancellationToken, read_done: CancellationToken) {
+    let _read_done = read_done.drop_guard();
+    let mut rate = RateState::default();
+    loop {
+        let frame = tokio::select! {
+            _ = cut.cancelled() => break,
+            frame = frames.next() => frame,
+        };
+        match frame {
+            Some(Ok(event)) => {
+                let inbound = match Inbound::screen(id, event, &mut rate, &rates) {
+                    Some(inbound) => inbound,
+                    None => continue,
+                };
+                if events.send(inbound).is_err() {
+                    return;
+                }
+            }
+            _ => break,
+        }
+    }
+    let _ = events.send(Inbound::Closed(id));
+}
+
+/// Writes what the game thread queues for one client, flushing whenever the queue
+/// runs dry. Ends when the client is dropped or closed, or a write fails.
+async fn write(mut frames: FramedWrite<OwnedWriteHalf, LurkCodec>, mut outgoing: UnboundedReceiver<Outbound>,
+               queue: &QueueDepth, reader_finished: CancellationToken) {
+    while let Some(mut next) = outgoing.recv().await {
+        loop {
+            match next {
+                Outbound::Message(lurkmsg) => {
+                    queue.pop(&lurkmsg);
+                    if frames.feed(lurkmsg).await.is_err() {
+                        return;
+                    }
+                }
+                Outbound::Close => {
+                    // Closing the sink shuts only our side, so the peer can read
+                    // everything before it sees the end of the stream.
+                    if frames.close().await.is_ok() {
+                        let _ = tokio::time::timeout(CLOSE_LINGER, reader_finished.cancelled()).await;
+                    }
+                    return;
+                }
+            }
+            next = match outgoing.try_recv() {
+                Ok(next) => next,
+                Err(_) => break,
+            };
+        }
+        if frames.flush().await.is_err() {
+            return;
+        }
+    }
+    let _ = frames.close().await;
+}
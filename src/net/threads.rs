@@ -0,0 +1,227 @@
+use super::{Inbound, Listener, NetConfig, Outbound, QueueDepth, ACCEPT_BACKOFF, CLOSE_LINGER};
+use crate::client::{ClientFactory, CLIENT_BUFFER_LIMIT};
+use crate::rate::{RateLimits, RateState};
+use crate::read_buffer::ReadBuffer;
+use crate::protocol::Error;
+use crate::write::{LurkWrite, LurkWriteMessage};
+use std::io::{self, BufWriter, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often an idle listener checks whether it should stop.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// The game thread's end of one client's connection.
+pub struct Connection {
+    outbox: Sender<Outbound>,
+    stream: TcpStream,
+    queue: Arc<QueueDepth>,
+}
+
+impl Connection {
+    /// False once the connection's writer has stopped.
+    pub fn send(&self, outbound: Outbound) -> bool {
+        if let Outbound::Message(lurkmsg) = &outbound {
+            self.queue.push(lurkmsg);
+        }
+        self.outbox.send(outbound).is_ok()
+    }
+
+    pub fn queue(&self) -> &Arc<QueueDepth> {
+        &self.queue
+    }
+
+    /// Closes the socket on the spot, dropping anything still queued.
+    pub fn cut(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Socket I/O, kept off the game thread. A thread per listener accepts connections, and each
+/// connection gets a thread decoding its events and one writing its messages, so
+/// neither a slow client nor a slow module holds up the other.
+pub struct Network {
+    inbound: Receiver<Inbound>,
+    accepting: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Network {
+    pub fn start(listeners: Vec<Listener>, factory: ClientFactory, config: NetConfig) -> Network {
+        let (inbound, receiver) = mpsc::channel();
+        let accepting = Arc::new(AtomicBool::new(true));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let factory = Arc::new(Mutex::new(factory));
+        for listener in listeners {
+            let (factory, config, inbound) = (factory.clone(), config.clone(), inbound.clone());
+            let (still_accepting, counter) = (accepting.clone(), connections.clone());
+            thread::spawn(move || accept(listener, factory, config, inbound, still_accepting, counter));
+        }
+        Network { inbound: receiver, accepting, connections }
+    }
+
+    /// The next thing that happened on the network, if anything has.
+    pub fn poll(&self) -> Option<Inbound> {
+        self.inbound.try_recv().ok()
+    }
+
+    /// Closes the listeners, so further connections are refused.
+    pub fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::SeqCst);
+    }
+
+    /// Connections whose writer is still running.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+fn accept(listener: Listener, factory: Arc<Mutex<ClientFactory>>, config: NetConfig, inbound: Sender<Inbound>,
+          accepting: Arc<AtomicBool>, connections: Arc<AtomicUsize>) {
+    // Polled rather than blocked on, so the listener is closed soon after
+    // `stop_accepting` instead of when the next client happens to connect.
+    if let Err(e) = listener.socket.set_nonblocking(true) {
+        error!("Failed to set up a listener: {}", e);
+        return;
+    }
+    while accepting.load(Ordering::SeqCst) {
+        let stream = match listener.socket.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to accept a connection: {}", e);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        let result = stream.set_nonblocking(false).and_then(|_| {
+            config.configure(&stream)?;
+            connect(stream, listener.trusted, &factory, &config, &inbound, &connections)
+        });
+        if let Err(e) = result {
+            warn!("Failed to accept a connection: {}", e);
+        }
+    }
+}
+
+fn connect(stream: TcpStream, trusted: bool, factory: &Mutex<ClientFactory>, config: &NetConfig,
+           inbound: &Sender<Inbound>, connections: &Arc<AtomicUsize>) -> io::Result<()> {
+    let peer = stream.peer_addr().ok();
+    // Held from the admission check until the client exists, so listeners admitting
+    // at once can't both take the last place.
+    let mut factory = factory.lock().unwrap();
+    if let Err(error) = factory.admit(peer, trusted) {
+        drop(factory);
+        reject(stream, error);
+        return Ok(());
+    }
+
+    let (outbox, outgoing) = mpsc::channel();
+    let reader: ReadBuffer = stream.try_clone()?.into();
+    let queue = Arc::new(QueueDepth::default());
+    let connection = Connection { outbox, stream: stream.try_clone()?, queue: queue.clone() };
+    let client = factory.create(peer, trusted, connection);
+    drop(factory);
+    let id = client.id();
+    let address = peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+    info!(client = id, peer = address, trusted = trusted; "Client connected.");
+    if inbound.send(Inbound::Joined(client)).is_err() {
+        return Ok(());
+    }
+
+    // The writer waits on this to learn the reader has finished.
+    let (read_done, reader_finished) = mpsc::channel::<()>();
+    let (events, rates) = (inbound.clone(), config.rates.clone());
+    thread::spawn(move || {
+        let _read_done = read_done;
+        read(reader, id, events, &rates);
+    });
+
+    connections.fetch_add(1, Ordering::SeqCst);
+    let connections = connections.clone();
+    thread::spawn(move || {
+        write(BufWriter::new(stream), outgoing, &queue, reader_finished);
+        connections.fetch_sub(1, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// Tells a connection that wasn't admitted why, then hangs up on it.
+fn reject(stream: TcpStream, error: Error) {
+    let mut writer = BufWriter::new(&stream);
+    let _ = writer.write_lurk_message(&LurkWriteMessage::Error(error)).and_then(|_| writer.flush());
+    drop(writer);
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// Decodes events until the connection closes, sends something that isn't LURK or
+/// overruns its buffer. Events over the rate limits are dropped here.
+fn read(mut reader: ReadBuffer, id: u128, events: Sender<Inbound>, rates: &RateLimits) {
+    let mut rate = RateState::default();
+    while reader.buffer().len() <= CLIENT_BUFFER_LIMIT {
+        match reader.decode() {
+            Ok(Some(event)) => {
+                let inbound = match Inbound::screen(id, event, &mut rate, rates) {
+                    Some(inbound) => inbound,
+                    None => continue,
+                };
+                if events.send(inbound).is_err() {
+                    return;
+                }
+            }
+            // Nothing complete is buffered, wait for more.
+            Ok(None) => match reader.fill_buf() {
+                Ok(fill) if fill > 0 => {}
+                _ => break,
+            },
+            Err(()) => break,
+        }
+    }
+    let _ = events.send(Inbound::Closed(id));
+}
+
+/// Writes what the game thread queues for one client, flushing whenever the queue
+/// runs dry. Ends when the client is dropped or closed, or a write fails.
+fn write(mut stream: BufWriter<TcpStream>, outgoing: Receiver<Outbound>, queue: &QueueDepth,
+         reader_finished: Receiver<()>) {
+    loop {
+        let next = match outgoing.try_recv() {
+            Ok(next) => next,
+            Err(TryRecvError::Empty) => {
+                if stream.flush().is_err() {
+                    break;
+                }
+                match outgoing.recv() {
+                    Ok(next) => next,
+                    Err(_) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match next {
+            Outbound::Message(lurkmsg) => {
+                queue.pop(&lurkmsg);
+                if stream.write_lurk_message(&lurkmsg).is_err() {
+                    break;
+                }
+            }
+            Outbound::Close => {
+                // Shutting only our side lets the peer read everything before it
+                // sees the end of the stream, while the reader drains what it sends.
+                if stream.flush().is_ok() && stream.get_ref().shutdown(Shutdown::Write).is_ok() {
+                    let _ = reader_finished.recv_timeout(CLOSE_LINGER);
+                }
+                break;
+            }
+        }
+    }
+    let _ = stream.flush();
+    let _ = stream.get_ref().shutdown(Shutdown::Both);
+}
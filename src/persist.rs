@@ -0,0 +1,186 @@
+use crate::lua::protocol_from_lua;
+use crate::module;
+use crate::protocol::{Character, Error, LurkName};
+use crate::read::LurkReadEvent;
+use crate::read_buffer::ReadBuffer;
+use crate::world::{Outgoing, WorldHandle};
+use crate::write::{LurkWrite, LurkWriteMessage};
+use rlua::{Context, Value};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const SAVE_DIR: &str = "saves";
+
+/// Saved player characters, one file per character under the saves directory,
+/// 'saves' in the module directory unless configured otherwise. Each file holds
+/// the character in its wire format.
+pub struct CharacterStore {
+    dir: PathBuf,
+}
+
+impl CharacterStore {
+    pub fn new(dir: &Path) -> CharacterStore {
+        CharacterStore {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, name: &LurkName) -> PathBuf {
+        let file: String = name.as_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(format!("{}.chr", file))
+    }
+
+    pub fn load(&self, name: &LurkName) -> io::Result<Option<Character>> {
+        let path = self.path(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(&path)?;
+        decode_character(&bytes).map(Some)
+    }
+
+    /// Writes to a temporary file first so a crash mid-save can't corrupt the old one.
+    pub fn save(&self, character: &Character) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_character(character)?;
+        let path = self.path(&character.name);
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, &bytes)?;
+        fs::rename(&temp, &path)
+    }
+
+    /// Saves the character played by a client, if it has one.
+    pub fn save_client(&self, ctx: Context, world: &WorldHandle, client_id: u128) {
+        let character = world.lock().player(client_id).map(|entity| entity.character.clone());
+        if let Some(character) = character {
+            self.save_character(ctx, character);
+        }
+    }
+
+    /// Saves every player character in play.
+    pub fn save_all(&self, ctx: Context, world: &WorldHandle) {
+        let characters: Vec<Character> = world.lock().players()
+            .map(|entity| entity.character.clone())
+            .collect();
+        for character in characters {
+            self.save_character(ctx, character);
+        }
+    }
+
+    /// A module can define `on_save(character)` returning false to skip the save
+    /// or a character to save in its place.
+    fn save_character(&self, ctx: Context, character: Character) {
+        let character = match module::hook(ctx, "on_save") {
+            Some(hook) => {
+                match hook.call::<_, Value>(character.clone()) {
+                    Ok(Value::Boolean(false)) => return,
+                    Ok(Value::Nil) | Ok(Value::Boolean(true)) => character,
+                    Ok(changed) => match protocol_from_lua(ctx, changed) {
+                        Ok(changed) => changed,
+                        Err(e) => {
+                            error!("Hook 'on_save' returned a bad character: {}", e);
+                            return;
+                        }
+                    },
+                    Err(e) => {
+                        error!("Hook 'on_save' failed: {}", e);
+                        return;
+                    }
+                }
+            }
+            None => character,
+        };
+
+        if let Err(e) = self.save(&character) {
+            error!("Failed to save character '{}': {}", character.name.to_string_lossy(), e);
+        }
+    }
+
+    /// Swaps a client's Character for the saved one with the same name, if there is
+    /// one and the client may claim it. Refusals come back as the error to send.
+    ///
+    /// By default a saved character can't be claimed while someone else is playing
+    /// it; a module can add its own checks with `on_claim(id, saved, requested)`.
+    pub fn restore(&self, ctx: Context, world: &WorldHandle, client_id: u128, requested: &mut Character) -> Result<(), Outgoing> {
+        // Checked before looking for a save, so a new character can't take the
+        // name of one in play either.
+        if let Err(e) = world.lock().check_name(client_id, &requested.name) {
+            return Err(vec![(client_id, LurkWriteMessage::Error(e))]);
+        }
+
+        let saved = match self.load(&requested.name) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                error!("Failed to load character '{}': {}", requested.name.to_string_lossy(), e);
+                return Ok(());
+            }
+        };
+
+        let refuse = |reason: &str| {
+            Err(vec![(client_id, LurkWriteMessage::Error(Error::new(Error::PLAYER_EXISTS, reason)))])
+        };
+
+        if let Some(hook) = module::hook(ctx, "on_claim") {
+            let result = hook.call::<_, Value>((client_id, saved.clone(), requested.clone()));
+            match result {
+                Ok(Value::Boolean(false)) => return refuse("That character belongs to someone else."),
+                Ok(_) => {}
+                Err(e) => {
+                    error!(client = client_id; "Hook 'on_claim' failed: {}", e);
+                    return refuse("That character can't be claimed right now.");
+                }
+            }
+        }
+
+        *requested = saved;
+        Ok(())
+    }
+}
+
+/// Saves are in the wire format, so they're read back with the client decoder.
+fn decode_character(bytes: &[u8]) -> io::Result<Character> {
+    let mut read: ReadBuffer<io::Empty> = io::empty().into();
+    read.extend(bytes);
+    match read.decode() {
+        Ok(Some(LurkReadEvent::Character(character))) => Ok(character),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "not a saved character")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_characters_decode_as_written() {
+        let character = Character {
+            name: LurkName::new("Bob"),
+            attack: 10,
+            health: -3,
+            gold: 7,
+            description: b"A traveller.".to_vec(),
+            ..Character::default()
+        };
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_character(&character).unwrap();
+
+        let decoded = decode_character(&bytes).unwrap();
+        assert!(decoded.name == character.name);
+        assert_eq!(decoded.attack, 10);
+        assert_eq!(decoded.health, -3);
+        assert_eq!(decoded.gold, 7);
+        assert_eq!(decoded.description, character.description);
+    }
+
+    #[test]
+    fn truncated_saves_are_rejected() {
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_character(&Character::default()).unwrap();
+        bytes.pop();
+        assert!(decode_character(&bytes).is_err());
+        assert!(decode_character(&[]).is_err());
+    }
+}
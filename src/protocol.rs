@@ -1,17 +1,83 @@
+use std::fmt;
 use std::io::BufRead;
 use std::io::Write;
 
-use lurk_macros::{LurkReadable, TypeCode};
+use crate::lua::{LuaField, LuaFields};
+use lurk_macros::{LuaFields, LurkReadable, TypeCode};
 use rlua::prelude::LuaTable;
 use rlua::Table;
 
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Default)]
 pub struct LurkName {
     pub bytes: [u8; 32],
 }
 
+/// Bytes in a name on the wire. Shorter names are padded with nulls.
+pub const NAME_LENGTH: usize = 32;
+
+/// Bytes a variable length text field can hold, since its length is sent as a u16.
+pub const TEXT_LENGTH: usize = u16::MAX as usize;
+
+impl LurkName {
+    /// A name from text that's known to be reasonable, cut at the last whole
+    /// character that fits.
+    pub fn new(name: &str) -> Self {
+        let mut len = name.len().min(NAME_LENGTH);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0u8; NAME_LENGTH];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        bytes.into()
+    }
+
+    /// A name holding exactly these bytes, which needn't be UTF-8.
+    pub fn from_bytes(name: &[u8]) -> Result<Self, NameError> {
+        if name.len() > NAME_LENGTH {
+            return Err(NameError::TooLong(name.len()));
+        }
+        if name.contains(&0) {
+            return Err(NameError::ContainsNull);
+        }
+        let mut bytes = [0u8; NAME_LENGTH];
+        bytes[..name.len()].copy_from_slice(name);
+        Ok(LurkName { bytes })
+    }
+
+    /// The name without its null padding.
+    pub fn as_bytes(&self) -> &[u8] {
+        let end = self.bytes.iter().position(|b| *b == 0).unwrap_or(NAME_LENGTH);
+        &self.bytes[..end]
+    }
+
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(self.as_bytes()).to_string()
+    }
+}
+
+pub enum NameError {
+    TooLong(usize),
+    ContainsNull,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameError::TooLong(len) => write!(f, "name is {} bytes, but at most {} fit", len, NAME_LENGTH),
+            NameError::ContainsNull => write!(f, "name contains a null byte"),
+        }
+    }
+}
+
+/// A name ends at its first null, so whatever follows is cleared to keep names
+/// that read the same equal.
 impl From<[u8; 32]> for LurkName {
-    fn from(bytes: [u8; 32]) -> Self {
+    fn from(mut bytes: [u8; 32]) -> Self {
+        if let Some(end) = bytes.iter().position(|b| *b == 0) {
+            for b in bytes[end..].iter_mut() {
+                *b = 0;
+            }
+        }
         LurkName { bytes }
     }
 }
@@ -25,7 +91,7 @@ pub trait LurkReadable {
     fn has_var_block() -> bool;
 }
 
-#[derive(TypeCode, LurkReadable)]
+#[derive(Clone, Default, TypeCode, LurkReadable, LuaFields)]
 #[Code = 1]
 #[StaticBlockSize = 67]
 #[VarBlock = true]
@@ -67,20 +133,39 @@ pub struct Loot {
 #[Code = 6]
 pub struct Start;
 
-#[derive(TypeCode)]
+#[derive(Clone, TypeCode)]
 #[Code = 7]
 pub struct Error {
     pub code: u8,
     pub message: Vec<u8>,
 }
 
-#[derive(TypeCode)]
+impl Error {
+    pub const OTHER: u8 = 0;
+    pub const BAD_ROOM: u8 = 1;
+    pub const PLAYER_EXISTS: u8 = 2;
+    pub const BAD_MONSTER: u8 = 3;
+    pub const STAT_ERROR: u8 = 4;
+    pub const NOT_READY: u8 = 5;
+    pub const NO_TARGET: u8 = 6;
+    pub const NO_FIGHT: u8 = 7;
+    pub const NO_PVP: u8 = 8;
+
+    pub fn new(code: u8, message: &str) -> Self {
+        Error {
+            code,
+            message: message.as_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, TypeCode)]
 #[Code = 8]
 pub struct Accept {
     pub code: u8,
 }
 
-#[derive(TypeCode)]
+#[derive(Clone, Default, TypeCode, LuaFields)]
 #[Code = 9]
 pub struct Room {
     pub number: u16,
@@ -90,6 +175,7 @@ pub struct Room {
 
 const FLAG: u8 = 0b1000_0000;
 bitflags! {
+    #[derive(Default)]
     pub struct CharacterFlags: u8 {
         const ALIVE       = FLAG;
         const JOIN_BATTLE = FLAG >> 1;
@@ -137,23 +223,25 @@ impl From<u8> for CharacterFlags {
     }
 }
 
-#[derive(TypeCode, LurkReadable)]
+#[derive(Clone, Default, TypeCode, LurkReadable, LuaFields)]
 #[Code = 10]
-#[StaticBlockSize = 86]
+#[StaticBlockSize = 48]
 #[VarBlock = true]
 pub struct Character {
     pub name: LurkName,
+    #[LuaFlatten = true]
     pub flags: CharacterFlags,
     pub attack: u16,
     pub defense: u16,
     pub regen: u16,
     pub health: i16,
     pub gold: u16,
+    #[LuaName = "room_number"]
     pub current_room_number: u16,
     pub description: Vec<u8>,
 }
 
-#[derive(TypeCode)]
+#[derive(Clone, Default, TypeCode, LuaFields)]
 #[Code = 11]
 pub struct Game {
     pub initial_points: u16,
@@ -165,7 +253,7 @@ pub struct Game {
 #[Code = 12]
 pub struct Leave;
 
-#[derive(TypeCode)]
+#[derive(Clone, Default, TypeCode, LuaFields)]
 #[Code = 13]
 pub struct Connection {
     pub room_number: u16,
@@ -173,7 +261,7 @@ pub struct Connection {
     pub description: Vec<u8>,
 }
 
-#[derive(TypeCode, LurkReadable)]
+#[derive(Clone, TypeCode, LurkReadable)]
 #[Code = 14]
 #[StaticBlockSize = 4]
 #[VarBlock = true]
@@ -182,3 +270,51 @@ pub struct Version {
     pub minor: u8,
     pub extensions: Vec<Vec<u8>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn padded(name: &[u8]) -> [u8; NAME_LENGTH] {
+        let mut bytes = [0u8; NAME_LENGTH];
+        bytes[..name.len()].copy_from_slice(name);
+        bytes
+    }
+
+    #[test]
+    fn bytes_after_the_first_null_are_ignored() {
+        let name = LurkName::from(padded(b"Bob\0xyz"));
+        assert!(name == LurkName::new("Bob"));
+        assert_eq!(name.as_bytes(), b"Bob");
+        assert_eq!(name.bytes, padded(b"Bob"));
+
+        let mut names = std::collections::HashSet::new();
+        names.insert(LurkName::new("Bob"));
+        assert!(names.contains(&name));
+    }
+
+    #[test]
+    fn text_names_stop_at_a_null() {
+        assert!(LurkName::new("Bob\0xyz") == LurkName::new("Bob"));
+    }
+
+    #[test]
+    fn a_full_name_keeps_every_byte() {
+        let full = [b'a'; NAME_LENGTH];
+        assert_eq!(LurkName::from(full).as_bytes(), &full[..]);
+    }
+
+    #[test]
+    fn text_names_are_cut_on_a_character_boundary() {
+        // 31 bytes of ASCII, then a two byte character that doesn't fit.
+        let text = format!("{}é", "a".repeat(31));
+        assert_eq!(LurkName::new(&text).as_bytes(), "a".repeat(31).as_bytes());
+    }
+
+    #[test]
+    fn names_from_bytes_are_checked() {
+        assert_eq!(LurkName::from_bytes(b"Bob").ok().map(|name| name.bytes), Some(padded(b"Bob")));
+        assert!(matches!(LurkName::from_bytes(&[b'a'; 33]), Err(NameError::TooLong(33))));
+        assert!(matches!(LurkName::from_bytes(b"Bob\0xyz"), Err(NameError::ContainsNull)));
+    }
+}
@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Event types a client sends, which is what limits are set on.
+const LIMITABLE: &[&str] = &[
+    "message", "change_room", "fight", "pvp_fight", "loot", "start", "character", "leave", "version",
+];
+
+/// Limits in force when none are given.
+pub const DEFAULT_LIMITS: &[&str] = &["message=4/8", "change_room=4/8", "fight=2/4", "pvp_fight=2/4", "loot=2/4"];
+
+/// How often a client may send one type of event, written `type=rate/burst`: up to
+/// `burst` at once, refilling at `rate` a second. The burst defaults to the rate.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateLimit {
+    pub event: String,
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<RateLimit, String> {
+        let (event, rate) = match text.find('=') {
+            Some(equals) => (&text[..equals], &text[equals + 1..]),
+            None => return Err(format!("expected 'type=rate/burst', not '{}'", text)),
+        };
+        if !LIMITABLE.contains(&event) {
+            return Err(format!("'{}' is not an event type a client sends", event));
+        }
+        let (per_second, burst) = match rate.find('/') {
+            Some(slash) => (&rate[..slash], Some(&rate[slash + 1..])),
+            None => (rate, None),
+        };
+        let number = |text: &str| text.parse::<f64>().ok()
+            .filter(|n| n.is_finite() && *n >= 0.0)
+            .ok_or_else(|| format!("'{}' is not a non-negative number", text));
+        let per_second = number(per_second)?;
+        let burst = match burst {
+            Some(burst) => number(burst)?.max(1.0),
+            None => per_second.max(1.0),
+        };
+        Ok(RateLimit { event: event.to_string(), per_second, burst })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<RateLimit, String> {
+        text.parse()
+    }
+}
+
+impl From<RateLimit> for String {
+    fn from(limit: RateLimit) -> String {
+        format!("{}={}/{}", limit.event, limit.per_second, limit.burst)
+    }
+}
+
+/// Adds limits to a list, each replacing any limit already on its type.
+pub fn merge(limits: &mut Vec<RateLimit>, more: impl IntoIterator<Item = RateLimit>) {
+    for limit in more {
+        limits.retain(|old| old.event != limit.event);
+        limits.push(limit);
+    }
+}
+
+/// Limits on how fast clients may send each type of event. Each time a client goes
+/// over one it earns a strike, and more than `strikes` of them within `window`
+/// gets it kicked. `None` never kicks. A later limit on a type overrides an earlier
+/// one, and a type without a limit, or with a zero rate, may be sent as fast as the
+/// client likes.
+#[derive(Clone)]
+pub struct RateLimits {
+    pub limits: Vec<RateLimit>,
+    pub strikes: Option<u32>,
+    /// Seconds over which strikes are forgiven, one by one. A client is told to
+    /// slow down at most once in this long; events it sends over the limits in
+    /// between are dropped without a reply.
+    pub window: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits { limits: vec![], strikes: None, window: 60.0 }
+    }
+}
+
+impl RateLimits {
+    fn get(&self, event: &str) -> Option<&RateLimit> {
+        self.limits.iter().rev()
+            .find(|limit| limit.event == event)
+            .filter(|limit| limit.per_second > 0.0)
+    }
+}
+
+/// Where a client's event falls against the limits. Only events within them are
+/// passed on.
+#[derive(Debug, PartialEq)]
+pub enum Rate {
+    Within,
+    /// Over the limit, and the client should be told to slow down.
+    Over,
+    /// Over the limit, but the client was told recently or is being kicked.
+    Muted,
+    /// Over the limit once too often.
+    Abusive,
+}
+
+/// One client's standing against the limits.
+#[derive(Default)]
+pub struct RateState {
+    buckets: HashMap<&'static str, Bucket>,
+    strikes: Option<Bucket>,
+    /// When the client was last told to slow down.
+    told: Option<Instant>,
+    abusive: bool,
+}
+
+impl RateState {
+    /// Spends a token on an event, which must be one of the types a client sends.
+    /// Once a client has been abusive, nothing more from it is within the limits.
+    pub fn check(&mut self, event: &'static str, limits: &RateLimits) -> Rate {
+        if self.abusive {
+            return Rate::Muted;
+        }
+        let limit = match limits.get(event) {
+            Some(limit) => limit,
+            None => return Rate::Within,
+        };
+        if self.buckets.entry(event).or_insert_with(|| Bucket::full(limit.burst)).take(limit.per_second, limit.burst) {
+            return Rate::Within;
+        }
+        if let Some(strikes) = limits.strikes {
+            let allowed = f64::from(strikes);
+            if !self.strikes.get_or_insert_with(|| Bucket::full(allowed)).take(1.0 / limits.window, allowed) {
+                self.abusive = true;
+                return Rate::Abusive;
+            }
+        }
+        match self.told {
+            Some(told) if told.elapsed().as_secs_f64() < limits.window => Rate::Muted,
+            _ => {
+                self.told = Some(Instant::now());
+                Rate::Over
+            }
+        }
+    }
+}
+
+/// A token bucket, refilled for the time since it was last drawn from.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64) -> Bucket {
+        Bucket { tokens: capacity, updated: Instant::now() }
+    }
+
+    fn take(&mut self, per_second: f64, capacity: f64) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(limits: &[&str], strikes: Option<u32>) -> RateLimits {
+        RateLimits { limits: limits.iter().map(|limit| limit.parse().unwrap()).collect(), strikes, window: 60.0 }
+    }
+
+    /// Moves a bucket's last refill back, as if the time had passed.
+    fn age(bucket: &mut Bucket, seconds: u64) {
+        bucket.updated -= Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn limits_parse_with_and_without_a_burst() {
+        let limit: RateLimit = "fight=2/5".parse().unwrap();
+        assert_eq!((limit.per_second, limit.burst), (2.0, 5.0));
+        let limit: RateLimit = "loot=0.5".parse().unwrap();
+        assert_eq!((limit.per_second, limit.burst), (0.5, 1.0));
+        for text in &["fight", "fight=", "fight=-1", "fight=1/x", "join=1", "fight=inf"] {
+            assert!(text.parse::<RateLimit>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn buckets_hold_a_burst_and_refill_over_time() {
+        let mut bucket = Bucket::full(2.0);
+        assert!(bucket.take(1.0, 2.0));
+        assert!(bucket.take(1.0, 2.0));
+        assert!(!bucket.take(1.0, 2.0));
+
+        age(&mut bucket, 1);
+        assert!(bucket.take(1.0, 2.0));
+        assert!(!bucket.take(1.0, 2.0));
+
+        // A long wait refills no more than the capacity.
+        age(&mut bucket, 100);
+        assert!(bucket.take(1.0, 2.0));
+        assert!(bucket.take(1.0, 2.0));
+        assert!(!bucket.take(1.0, 2.0));
+    }
+
+    #[test]
+    fn later_limits_override_earlier_ones() {
+        let limits = limits(&["fight=1/1", "fight=0", "loot=1/1", "loot=1/3"], None);
+        let mut state = RateState::default();
+        for _ in 0..10 {
+            assert_eq!(state.check("fight", &limits), Rate::Within);
+        }
+        for _ in 0..3 {
+            assert_eq!(state.check("loot", &limits), Rate::Within);
+        }
+        assert_eq!(state.check("loot", &limits), Rate::Over);
+        assert_eq!(state.check("message", &limits), Rate::Within);
+    }
+
+    #[test]
+    fn clients_are_told_once_a_strike_window() {
+        let limits = limits(&["fight=1/1"], None);
+        let mut state = RateState::default();
+        assert_eq!(state.check("fight", &limits), Rate::Within);
+        assert_eq!(state.check("fight", &limits), Rate::Over);
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+
+        state.told = state.told.map(|told| told - Duration::from_secs(61));
+        assert_eq!(state.check("fight", &limits), Rate::Over);
+
+        let limits = RateLimits { window: 5.0, ..limits };
+        state.told = state.told.map(|told| told - Duration::from_secs(6));
+        assert_eq!(state.check("fight", &limits), Rate::Over);
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+    }
+
+    #[test]
+    fn too_many_strikes_are_abusive() {
+        let limits = limits(&["fight=1/1"], Some(2));
+        let mut state = RateState::default();
+        assert_eq!(state.check("fight", &limits), Rate::Within);
+        assert_eq!(state.check("fight", &limits), Rate::Over);
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+        assert_eq!(state.check("fight", &limits), Rate::Abusive);
+        // Everything after is dropped while the client is kicked.
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+        assert_eq!(state.check("start", &limits), Rate::Muted);
+    }
+
+    #[test]
+    fn strikes_are_forgiven_over_the_window() {
+        let limits = limits(&["fight=1/1"], Some(1));
+        let mut state = RateState::default();
+        assert_eq!(state.check("fight", &limits), Rate::Within);
+        assert_eq!(state.check("fight", &limits), Rate::Over);
+
+        if let Some(strikes) = state.strikes.as_mut() {
+            age(strikes, 60);
+        }
+        assert_eq!(state.check("fight", &limits), Rate::Muted);
+        assert_eq!(state.check("fight", &limits), Rate::Abusive);
+    }
+}
@@ -59,6 +59,23 @@ pub enum LurkReadEvent {
     Version(Version),
 }
 
+impl LurkReadEvent {
+    /// The event's type, as modules and rate limits name it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LurkReadEvent::Message(_) => "message",
+            LurkReadEvent::ChangeRoom(_) => "change_room",
+            LurkReadEvent::Fight => "fight",
+            LurkReadEvent::PVPFight(_) => "pvp_fight",
+            LurkReadEvent::Loot(_) => "loot",
+            LurkReadEvent::Start => "start",
+            LurkReadEvent::Character(_) => "character",
+            LurkReadEvent::Leave => "leave",
+            LurkReadEvent::Version(_) => "version",
+        }
+    }
+}
+
 pub trait LurkRead {
     fn poll_message(&self) -> LurkPollState;
     fn read_message(&mut self) -> LurkReadResult<Message>;
@@ -88,10 +105,37 @@ pub trait LurkRead {
 }
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{BufReader, Read, BufRead};
+use std::io::{self, BufReader, Read, BufRead};
 use std::net::TcpStream;
 use crate::read_buffer::ReadBuffer;
-use std::error::Error;
+
+/// Where events are decoded from: the bytes buffered so far, read from the front
+/// as they're decoded, and a way to buffer more.
+pub trait LurkSource: Read {
+    fn buffer(&self) -> &[u8];
+    fn fill_buf(&mut self) -> io::Result<usize>;
+}
+
+impl<S: Read> LurkSource for ReadBuffer<S> {
+    fn buffer(&self) -> &[u8] {
+        ReadBuffer::buffer(self)
+    }
+
+    fn fill_buf(&mut self) -> io::Result<usize> {
+        ReadBuffer::fill_buf(self)
+    }
+}
+
+/// Bytes already in memory, such as a codec's, which is all there will be.
+impl LurkSource for &[u8] {
+    fn buffer(&self) -> &[u8] {
+        self
+    }
+
+    fn fill_buf(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
 
 fn start_match<T: TypeCode>(buffer: &[u8]) -> bool {
     if buffer.len() > 0 {
@@ -125,7 +169,37 @@ fn poll<T: TypeCode + LurkReadable>(buffer: &[u8]) -> LurkPollState {
     }
 }
 
-impl LurkRead for ReadBuffer {
+impl<S: Read> ReadBuffer<S> {
+    /// The next complete event in the buffer, without reading from the source.
+    /// `Ok(None)` until a whole message has arrived; an error means the bytes
+    /// aren't LURK.
+    pub fn decode(&mut self) -> LurkReadResult<Option<LurkReadEvent>> {
+        decode(self)
+    }
+}
+
+/// The next complete event at the front of the source's buffer, which is taken
+/// off the front. Nothing is taken while only part of a message has arrived.
+pub fn decode<T: LurkSource>(source: &mut T) -> LurkReadResult<Option<LurkReadEvent>> {
+    if source.buffer().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(match source.poll_lurk()? {
+        LurkPollEvent::Pending => return Ok(None),
+        LurkPollEvent::Bad => return Err(()),
+        LurkPollEvent::Message(msg) => LurkReadEvent::Message(msg),
+        LurkPollEvent::ChangeRoom(chgrm) => LurkReadEvent::ChangeRoom(chgrm),
+        LurkPollEvent::PVPFight(pvpfight) => LurkReadEvent::PVPFight(pvpfight),
+        LurkPollEvent::Loot(loot) => LurkReadEvent::Loot(loot),
+        LurkPollEvent::Character(ch) => LurkReadEvent::Character(ch),
+        LurkPollEvent::Fight => LurkReadEvent::Fight,
+        LurkPollEvent::Start => LurkReadEvent::Start,
+        LurkPollEvent::Leave => LurkReadEvent::Leave,
+        LurkPollEvent::Version(vers) => LurkReadEvent::Version(vers),
+    }))
+}
+
+impl<T: LurkSource> LurkRead for T {
     fn poll_message(&self) -> LurkPollState {
         let buffer = self.buffer();
 
@@ -315,17 +389,17 @@ impl LurkRead for ReadBuffer {
 
     fn poll_lurk(&mut self) -> LurkReadResult<LurkPollEvent> {
         use std::io;
-        // Attempt to fill the buffer if it's empty. If we don't get anything back,
-        // (empty fill, would block error) then return pending.
+        // Attempt to fill the buffer if it's empty. If nothing is waiting (would block
+        // error) then return pending; an empty fill means the peer has closed.
         if self.buffer().is_empty() {
             match self.fill_buf() {
                 Ok(fill) => if fill == 0 {
-                    return Ok(LurkPollEvent::Pending);
+                    return Err(());
                 },
                 Err(e) => if e.kind() == io::ErrorKind::WouldBlock {
                     return Ok(LurkPollEvent::Pending);
                 } else {
-                    eprintln!("{}", e.description());
+                    debug!("Failed to read from a client: {}", e);
                     return Err(());
                 }
             }
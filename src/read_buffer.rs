@@ -1,42 +1,45 @@
 use std::io::{Read, Error};
 use std::net::TcpStream;
 
-pub struct ReadBuffer {
-    source: TcpStream,
+/// Bytes read from a client but not yet decoded. The source is where `fill_buf`
+/// reads more from; a codec fed its bytes some other way uses `io::Empty`.
+pub struct ReadBuffer<S = TcpStream> {
+    source: S,
     data: Vec<u8>,
 }
 
-impl Read for ReadBuffer {
+impl<S> Read for ReadBuffer<S> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
         let num = self.data.len().min(buf.len());
         let take: Vec<u8>  = self.data.drain(0..num).collect();
-        println!("New length is {}.", self.data.len());
         buf[0..num].copy_from_slice(&take[..]);
         Ok(num)
     }
 }
 
-impl ReadBuffer {
+impl<S> ReadBuffer<S> {
     pub fn buffer(&self) -> &[u8] {
         &self.data
     }
 
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+impl<S: Read> ReadBuffer<S> {
     pub fn fill_buf(&mut self) -> Result<usize, Error> {
         let mut temp = [0u8; 32_000];
         let read = self.source.read(&mut temp)?;
         self.data.append(&mut temp[..read].to_vec());
         Ok((read))
     }
-
-    pub fn len(&self) -> usize {
-        self.data.len()
-    }
 }
 
-impl From<TcpStream> for ReadBuffer {
-    fn from(stream: TcpStream) -> Self {
+impl<S: Read> From<S> for ReadBuffer<S> {
+    fn from(source: S) -> Self {
         Self {
-            source: stream,
+            source,
             data: vec![],
         }
     }
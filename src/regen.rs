@@ -0,0 +1,165 @@
+use crate::protocol::LurkName;
+use crate::world::{Entity, Outgoing, WorldHandle};
+use crate::write::LurkWriteMessage;
+
+#[derive(Clone)]
+pub struct RegenRules {
+    /// Ticks between regeneration passes, zero turns regeneration off.
+    pub interval_ticks: u32,
+    /// Characters who fought within `combat_cooldown_ticks` don't regenerate.
+    pub skip_in_combat: bool,
+    pub combat_cooldown_ticks: u32,
+}
+
+impl Default for RegenRules {
+    fn default() -> Self {
+        RegenRules {
+            interval_ticks: 50,
+            skip_in_combat: true,
+            combat_cooldown_ticks: 50,
+        }
+    }
+}
+
+/// Raises every living character's health by its regen, up to its maximum. Monsters
+/// spawned with their own interval regenerate on that instead of the world's.
+pub fn tick(world: &WorldHandle) -> Outgoing {
+    let mut out = Outgoing::new();
+    let mut world = world.lock();
+    let tick = world.tick;
+    let interval = world.regen.interval_ticks;
+    let skip_in_combat = world.regen.skip_in_combat;
+    let cooldown = u64::from(world.regen.combat_cooldown_ticks);
+    let own = world.monsters.regen_intervals();
+    let due = |entity: &Entity| {
+        let interval = own.get(&entity.character.name).copied().unwrap_or(interval);
+        interval > 0 && tick % u64::from(interval) == 0
+    };
+
+    let mut healed: Vec<LurkName> = vec![];
+    for entity in world.entities_mut() {
+        if !due(entity) || !entity.is_alive() || entity.character.regen == 0 || entity.character.health >= entity.max_health {
+            continue;
+        }
+        let fighting = match entity.last_fought {
+            Some(fought) => tick.saturating_sub(fought) < cooldown,
+            None => false,
+        };
+        if skip_in_combat && fighting {
+            continue;
+        }
+        let health = i32::from(entity.character.health) + i32::from(entity.character.regen);
+        entity.character.health = health.min(i32::from(entity.max_health)) as i16;
+        healed.push(entity.character.name);
+    }
+
+    for name in healed.iter() {
+        if let Some(entity) = world.get(name) {
+            world.broadcast(entity.room(), &LurkWriteMessage::Character(entity.character.clone()), &mut out);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monster::SpawnDefinition;
+    use crate::protocol::{Character, CharacterFlags};
+
+    const PLAYER: u128 = 1;
+
+    /// A character with a maximum of 100 health who regenerates 10 a pass.
+    fn character(name: &str) -> Character {
+        let mut character = Character {
+            name: LurkName::new(name),
+            health: 100,
+            regen: 10,
+            current_room_number: 1,
+            ..Character::default()
+        };
+        character.flags.insert(CharacterFlags::ALIVE);
+        character
+    }
+
+    fn world(interval_ticks: u32, combat_cooldown_ticks: u32) -> WorldHandle {
+        let world = WorldHandle::default();
+        world.lock().regen = RegenRules { interval_ticks, skip_in_combat: true, combat_cooldown_ticks };
+        world
+    }
+
+    fn wound(world: &WorldHandle, name: &str, health: i16) {
+        world.lock().get_mut(&LurkName::new(name)).unwrap().character.health = health;
+    }
+
+    fn health(world: &WorldHandle, name: &str) -> i16 {
+        world.lock().get(&LurkName::new(name)).unwrap().character.health
+    }
+
+    /// Runs regeneration on the given tick.
+    fn tick_at(world: &WorldHandle, tick: u64) -> Outgoing {
+        world.lock().tick = tick;
+        super::tick(world)
+    }
+
+    #[test]
+    fn health_rises_on_the_interval_up_to_the_maximum() {
+        let world = world(5, 0);
+        assert!(world.lock().add_player(PLAYER, character("Hero")).is_ok());
+        wound(&world, "Hero", 85);
+
+        assert!(tick_at(&world, 3).is_empty());
+        assert_eq!(health(&world, "Hero"), 85);
+        let out = tick_at(&world, 5);
+        assert!(matches!(out.as_slice(), [(PLAYER, LurkWriteMessage::Character(_))]));
+        assert_eq!(health(&world, "Hero"), 95);
+        tick_at(&world, 10);
+        assert_eq!(health(&world, "Hero"), 100);
+        assert!(tick_at(&world, 15).is_empty());
+        assert_eq!(health(&world, "Hero"), 100);
+    }
+
+    #[test]
+    fn monsters_with_their_own_interval_use_it() {
+        let world = world(5, 0);
+        {
+            let mut world = world.lock();
+            assert!(world.add_monster(character("Rat")).is_ok());
+            assert!(world.add_monster(character("Bat")).is_ok());
+            world.monsters.define(SpawnDefinition {
+                room: 1,
+                template: character("Rat"),
+                respawn_ticks: 0,
+                wander_chance: 0,
+                wander_ticks: 0,
+                aggressive: false,
+                regen_ticks: Some(3),
+            });
+        }
+        wound(&world, "Rat", 50);
+        wound(&world, "Bat", 50);
+
+        tick_at(&world, 3);
+        assert_eq!((health(&world, "Rat"), health(&world, "Bat")), (60, 50));
+        tick_at(&world, 5);
+        assert_eq!((health(&world, "Rat"), health(&world, "Bat")), (60, 60));
+    }
+
+    #[test]
+    fn characters_who_just_fought_are_skipped() {
+        let world = world(5, 10);
+        assert!(world.lock().add_player(PLAYER, character("Hero")).is_ok());
+        wound(&world, "Hero", 50);
+        world.lock().get_mut(&LurkName::new("Hero")).unwrap().last_fought = Some(2);
+
+        tick_at(&world, 10);
+        assert_eq!(health(&world, "Hero"), 50);
+        tick_at(&world, 15);
+        assert_eq!(health(&world, "Hero"), 60);
+
+        world.lock().regen.skip_in_combat = false;
+        world.lock().get_mut(&LurkName::new("Hero")).unwrap().last_fought = Some(19);
+        tick_at(&world, 20);
+        assert_eq!(health(&world, "Hero"), 70);
+    }
+}
@@ -0,0 +1,133 @@
+use rlua::{Context, Value};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Ticks between scans of the module directory when watching it.
+pub const WATCH_INTERVAL: u32 = 10;
+
+/// Tables deeper than this are left out of a snapshot, which also cuts cycles.
+const MAX_DEPTH: usize = 32;
+
+/// Globals every fresh state already has.
+const LIBRARY_GLOBALS: &[&str] = &["_G", "_VERSION", "string", "table", "math", "utf8"];
+
+/// Notices when any Lua file under the module directory is added, removed or modified.
+pub struct ModuleWatcher {
+    root: PathBuf,
+    fingerprint: Vec<(PathBuf, SystemTime)>,
+}
+
+impl ModuleWatcher {
+    pub fn new(module: &str) -> ModuleWatcher {
+        let root = PathBuf::from(module);
+        let fingerprint = fingerprint(&root);
+        ModuleWatcher { root, fingerprint }
+    }
+
+    pub fn changed(&mut self) -> bool {
+        let current = fingerprint(&self.root);
+        if current == self.fingerprint {
+            return false;
+        }
+        self.fingerprint = current;
+        true
+    }
+}
+
+fn fingerprint(root: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let mut files = vec![];
+    collect_modified(root, &mut files);
+    files.sort();
+    files
+}
+
+fn collect_modified(dir: &Path, files: &mut Vec<(PathBuf, SystemTime)>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_modified(&path, files);
+        } else if path.extension() == Some(OsStr::new("lua")) {
+            if let Ok(modified) = entry.metadata().and_then(|meta| meta.modified()) {
+                files.push((path, modified));
+            }
+        }
+    }
+}
+
+/// The plain data in a Lua state's globals, copied out so it can be handed to the
+/// module's replacement. Functions, userdata and coroutines can't cross between
+/// states and are left out. A table reachable by two paths is copied twice.
+pub enum Snapshot {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    String(Vec<u8>),
+    Table(Vec<(Snapshot, Snapshot)>),
+}
+
+impl Snapshot {
+    pub fn take(ctx: Context) -> rlua::Result<Snapshot> {
+        let mut globals = vec![];
+        for pair in ctx.globals().pairs::<Value, Value>() {
+            let (key, value) = pair?;
+            if let Value::String(name) = &key {
+                if LIBRARY_GLOBALS.iter().any(|library| library.as_bytes() == name.as_bytes()) {
+                    continue;
+                }
+            }
+            if let (Some(key), Some(value)) = (copy(key, 0)?, copy(value, 0)?) {
+                globals.push((key, value));
+            }
+        }
+        Ok(Snapshot::Table(globals))
+    }
+
+    /// Any single value, with whatever can't be copied left out.
+    pub fn of(value: Value) -> rlua::Result<Snapshot> {
+        Ok(copy(value, 0)?.unwrap_or(Snapshot::Nil))
+    }
+
+    pub fn to_lua<'lua>(&self, ctx: Context<'lua>) -> rlua::Result<Value<'lua>> {
+        Ok(match self {
+            Snapshot::Nil => Value::Nil,
+            Snapshot::Boolean(b) => Value::Boolean(*b),
+            Snapshot::Integer(i) => Value::Integer(*i),
+            Snapshot::Number(n) => Value::Number(*n),
+            Snapshot::String(bytes) => Value::String(ctx.create_string(bytes)?),
+            Snapshot::Table(pairs) => {
+                let table = ctx.create_table()?;
+                for (key, value) in pairs.iter() {
+                    table.set(key.to_lua(ctx)?, value.to_lua(ctx)?)?;
+                }
+                Value::Table(table)
+            }
+        })
+    }
+}
+
+fn copy(value: Value, depth: usize) -> rlua::Result<Option<Snapshot>> {
+    Ok(Some(match value {
+        Value::Nil => Snapshot::Nil,
+        Value::Boolean(b) => Snapshot::Boolean(b),
+        Value::Integer(i) => Snapshot::Integer(i),
+        Value::Number(n) => Snapshot::Number(n),
+        Value::String(s) => Snapshot::String(s.as_bytes().to_vec()),
+        Value::Table(table) if depth < MAX_DEPTH => {
+            let mut pairs = vec![];
+            for pair in table.pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                if let (Some(key), Some(value)) = (copy(key, depth + 1)?, copy(value, depth + 1)?) {
+                    pairs.push((key, value));
+                }
+            }
+            Snapshot::Table(pairs)
+        }
+        _ => return Ok(None),
+    }))
+}
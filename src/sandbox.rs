@@ -0,0 +1,134 @@
+use rlua::{Context, HookTriggers, Lua, StdLib};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How many instructions run between budget checks. Lower is more precise but slower.
+const INSTRUCTION_STEP: u32 = 1000;
+
+/// Base library functions that reach the file system.
+const UNSAFE_GLOBALS: &[&str] = &["dofile", "loadfile"];
+
+pub struct Limits {
+    /// Instructions a single dispatch into the module may run, `None` for no limit.
+    pub instructions: Option<u64>,
+    /// Bytes the whole Lua state may allocate, `None` for no limit.
+    pub memory: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            instructions: Some(10_000_000),
+            memory: Some(64 * 1024 * 1024),
+        }
+    }
+}
+
+/// No io, os or debug libraries. The base library's file loaders are removed in
+/// `Sandbox::new`, so module scripts only reach files through `require`, which is
+/// kept to the module directory.
+pub fn libraries() -> StdLib {
+    StdLib::BASE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH
+}
+
+/// A Lua state for running module code. Hooks that run past the instruction
+/// budget or the memory limit fail with an error like any other, so only the
+/// offending call is aborted.
+pub struct Sandbox {
+    lua: Lua,
+    used: Arc<AtomicU64>,
+}
+
+impl Sandbox {
+    pub fn new(limits: &Limits) -> Sandbox {
+        let lua = Lua::new_with(libraries());
+        let used = Arc::new(AtomicU64::new(0));
+
+        lua.context(|ctx| {
+            for name in UNSAFE_GLOBALS {
+                ctx.globals()
+                    .set(*name, rlua::Value::Nil)
+                    .expect("Failed to remove unsafe Lua global.");
+            }
+        });
+
+        if let Some(limit) = limits.instructions {
+            let counter = used.clone();
+            let triggers = HookTriggers {
+                every_nth_instruction: Some(INSTRUCTION_STEP),
+                ..Default::default()
+            };
+            lua.set_hook(triggers, move |_, debug| {
+                let step = u64::from(INSTRUCTION_STEP);
+                let total = counter.fetch_add(step, Ordering::Relaxed) + step;
+                if total <= limit {
+                    return Ok(());
+                }
+                // Callers only see a wrapped callback error, so say what happened here.
+                // Later trips in the same dispatch come from code that caught the first.
+                if total - step <= limit {
+                    let source = debug.source();
+                    let place = String::from_utf8_lossy(source.short_src.unwrap_or(b"?"));
+                    warn!("Module exceeded its limit of {} instructions in {}:{}.", limit, place, debug.curr_line());
+                }
+                Err(rlua::Error::RuntimeError(format!("instruction limit of {} exceeded", limit)))
+            });
+        }
+        lua.set_memory_limit(limits.memory);
+
+        Sandbox { lua, used }
+    }
+
+    pub fn lua(&self) -> &Lua {
+        &self.lua
+    }
+
+    /// Runs one dispatch into the module with a fresh instruction budget.
+    pub fn dispatch<R, F: FnOnce(Context) -> R>(&self, f: F) -> R {
+        self.used.store(0, Ordering::Relaxed);
+        self.lua.context(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(instructions: Option<u64>, memory: Option<usize>) -> Sandbox {
+        Sandbox::new(&Limits { instructions, memory })
+    }
+
+    #[test]
+    fn a_runaway_loop_is_aborted_and_the_next_dispatch_runs() {
+        let sandbox = sandbox(Some(100_000), None);
+        match sandbox.dispatch(|ctx| ctx.load("while true do end").exec()) {
+            Err(rlua::Error::CallbackError { cause, .. }) => {
+                assert_eq!(cause.to_string(), "runtime error: instruction limit of 100000 exceeded");
+            }
+            other => panic!("expected the hook's error, got {:?}", other),
+        }
+
+        // Each dispatch has a budget of its own.
+        let sum: i64 = sandbox.dispatch(|ctx| ctx.load("local n = 0 for i = 1, 1000 do n = n + i end return n").eval()).unwrap();
+        assert_eq!(sum, 500_500);
+    }
+
+    #[test]
+    fn a_runaway_allocation_is_stopped() {
+        let sandbox = sandbox(None, Some(4 * 1024 * 1024));
+        let grown = sandbox.dispatch(|ctx| ctx.load("local t = {} for i = 1, 1e8 do t[i] = i end").exec());
+        assert!(matches!(grown, Err(rlua::Error::MemoryError(_))), "{:?}", grown.err());
+
+        let small: String = sandbox.dispatch(|ctx| ctx.load("return string.rep('a', 3)").eval()).unwrap();
+        assert_eq!(small, "aaa");
+    }
+
+    #[test]
+    fn file_and_system_access_is_removed() {
+        let sandbox = sandbox(None, None);
+        for name in &["io", "os", "debug", "dofile", "loadfile", "require"] {
+            let unset = sandbox.dispatch(|ctx| matches!(ctx.globals().get(*name), Ok(rlua::Value::Nil)));
+            assert!(unset, "{} is still set", name);
+        }
+    }
+}
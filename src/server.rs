@@ -1,74 +1,516 @@
-use crate::client::{Client, ClientFactory, ClientEvent};
+use crate::access::{AccessHandle, Admission, ListWatcher};
+use crate::client::{Client, ClientFactory, ClientEvent, ClientEventKind, QueueLimits};
 use crate::read::LurkReadEvent;
-use rlua::prelude::LuaTable;
-use rlua::Value::Nil;
-use rlua::{Lua, UserData, UserDataMethods};
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-use std::io::Error;
-use std::net::TcpStream;
-use crate::lua::ClientEventBuffer;
-use crate::cli::Args;
+use std::collections::HashMap;
+use crate::lua::{self, ClientEventBuffer, ClientWriteBuffer};
+use crate::config::{Config, NetworkConfig};
+use crate::module;
+use crate::net::{Inbound, Listener, NetConfig, Network};
+use crate::combat;
+use crate::loot;
+use crate::monster;
+use crate::regen;
+use crate::reload::{self, ModuleWatcher, Snapshot};
+use crate::session::{ConnectionState, IdleTimeouts, Sessions};
+use crate::signal;
+use crate::persist::CharacterStore;
+use crate::protocol;
+use crate::rate::RateLimits;
+use crate::store::StoreHandle;
+use crate::sandbox::{Limits, Sandbox};
+use crate::world::{Outgoing, WorldHandle};
+use crate::write::LurkWriteMessage;
+use rlua::Context;
 
-pub fn server(args: &Args) {
-    use std::collections::HashMap;
-    use std::net::TcpListener;
-    use std::fs::read_to_string;
+pub fn server(config: &Config) {
+    let module = config.module();
+    let (network_config, limits_config) = (&config.network, &config.limits);
 
-    let mut client_factory = ClientFactory::default();
+    let access = AccessHandle::open(limits_config.access_list.as_deref()).unwrap_or_else(|e| {
+        exit_invalid(format!("failed to load access list '{}': {}", limits_config.access_list.as_ref().unwrap().display(), e))
+    });
+    let client_factory = ClientFactory::new(QueueLimits {
+        messages: Some(limits_config.max_queue_messages).filter(|n| *n > 0),
+        bytes: Some(limits_config.max_queue_bytes).filter(|n| *n > 0),
+        policy: limits_config.slow_client,
+    }, Admission {
+        max_clients: Some(limits_config.max_clients).filter(|n| *n > 0),
+        max_per_ip: Some(limits_config.max_clients_per_ip).filter(|n| *n > 0),
+        access: access.clone(),
+    });
 
-    let server_address = format!("0.0.0.0:{}", args.port);
+    let listeners = bind(network_config).unwrap_or_else(exit_invalid);
 
-    let main_script_path = format!("{}/main.lua", &args.module);
-    let script_src = read_to_string(&main_script_path).expect("Failed to load server script 'main.lua'.");
+    let mut events_buffer = ClientEventBuffer::default();
 
-    let listener: TcpListener = TcpListener::bind(server_address).unwrap_or_else(|_| panic!(""));
+    let world = WorldHandle::default();
+    world.lock().game = config.game.to_game();
+    let store = CharacterStore::new(config.saves());
 
-    listener
-        .set_nonblocking(true)
-        .expect("Failed to set listener to non-blocking.");
+    let kv_path = config.store();
+    let kv = StoreHandle::open(kv_path)
+        .unwrap_or_else(|e| exit_invalid(format!("failed to open store '{}': {}", kv_path.display(), e)));
 
-    let mut events_buffer = ClientEventBuffer::default();
-    let buffer_lua_handle = events_buffer.clone();
+    let limits = config.lua.limits();
+    let globals = Globals {
+        events: events_buffer.clone(),
+        outbox: ClientWriteBuffer::default(),
+        world: world.clone(),
+        kv: kv.clone(),
+        sessions: client_factory.sessions().clone(),
+    };
+    let mut sandbox = start_module(module, &limits, &globals)
+        .unwrap_or_else(|e| exit_invalid(format!("failed to load module '{}': {}", module, e)));
+    kv.finish_dispatch(true);
 
-    let lua = Lua::new();
-    lua.context(|ctx| {
-        ctx.globals()
-            .set("Events", buffer_lua_handle)
-            .expect("Failed to globalize Lua events table.");
-    });
+    signal::install();
+    let mut watcher = if config.lua.watch { Some(ModuleWatcher::new(module)) } else { None };
+    let mut access_watcher = access.path().map(ListWatcher::new);
 
+    let idle = IdleTimeouts {
+        lobby: Some(limits_config.lobby_timeout).filter(|n| *n > 0).map(std::time::Duration::from_secs),
+        playing: Some(limits_config.idle_timeout).filter(|n| *n > 0).map(std::time::Duration::from_secs),
+        warning: std::time::Duration::from_secs(limits_config.idle_warning),
+    };
+    let rates = RateLimits {
+        limits: limits_config.rate_limits.clone(),
+        strikes: Some(limits_config.rate_strikes).filter(|n| *n > 0),
+        window: limits_config.rate_window.max(1) as f64,
+    };
+    let net_config = NetConfig {
+        keepalive: network_config.keepalive.map(std::time::Duration::from_secs),
+        rates,
+    };
+    let network = Network::start(listeners, client_factory, net_config);
     let mut clients: HashMap<u128, Client> = HashMap::new();
 
-    let running = true;
+    let tick = std::time::Duration::from_millis(config.server.tick_ms);
+
+    let mut running = true;
 
     while running {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(s) => {
-                    if s.set_nonblocking(true).is_ok() {
-                        if let Ok(new_client) = client_factory.create(s) {
-                            events_buffer.add(new_client.join());
-                            clients.insert(new_client.id(), new_client);
+        let tick_start = std::time::Instant::now();
+
+        // SIGHUP reloads both the access list and the module. Otherwise each is
+        // reloaded when its files change, the module only if it's watched.
+        let hangup = signal::take_hangup();
+        let scan = world.lock().every(reload::WATCH_INTERVAL);
+        let list_changed = scan && access_watcher.as_mut().is_some_and(ListWatcher::changed);
+        if hangup || list_changed {
+            reload_access(&access);
+        }
+        let changed = scan && watcher.as_mut().is_some_and(ModuleWatcher::changed);
+        if hangup || changed {
+            reload(module, &limits, &globals, &mut sandbox);
+        }
+
+        let mut polled: Vec<ClientEvent> = vec![];
+
+        while let Some(inbound) = network.poll() {
+            match inbound {
+                Inbound::Joined(client) => {
+                    polled.push(client.join());
+                    clients.insert(client.id(), client);
+                }
+                Inbound::Event(client_event) => {
+                    // Anything a removed client sent on its way out is dropped.
+                    if let Some(client) = clients.get_mut(&client_event.client_id()) {
+                        client.touch();
+                        if client.listening() {
+                            polled.push(client_event);
                         }
                     }
                 }
-                _ => { break; }
+                Inbound::Throttled(id, event) => {
+                    if let Some(client) = clients.get_mut(&id) {
+                        client.touch();
+                        client.throttle(event);
+                    }
+                }
+                Inbound::Flooding(id, event) => {
+                    if let Some(client) = clients.get_mut(&id) {
+                        client.flooded(event);
+                    }
+                }
+                Inbound::Closed(id) => {
+                    if let Some(client) = clients.get_mut(&id) {
+                        client.poison();
+                    }
+                }
+            }
+        }
+
+        for client in clients.values_mut() {
+            client.take_disconnect();
+            client.send_held();
+        }
+        check_idle(&sandbox, &globals, &mut clients, &idle);
+
+        // Departed clients are kept until their 'left' event has been handled, so
+        // listeners can still see their session.
+        let mut departed: Vec<Client> = vec![];
+        let leaving: Vec<u128> = clients.values()
+            .filter(|client| client.poisoned() || client.kicked())
+            .map(Client::id)
+            .collect();
+        for id in leaving {
+            if let Some(client) = clients.remove(&id) {
+                info!(client = id, kicked = client.kicked(); "Client left.");
+                sandbox.dispatch(|ctx| store.save_client(ctx, &world, id));
+                world.lock().remove_client(id);
+                polled.push(client.left());
+                departed.push(client);
             }
         }
 
-        for (_, client) in clients.iter_mut() {
-            while let Some(client_event) = client.poll_event() {
-                events_buffer.add(client_event);
+        for mut client_event in polled {
+            let client_id = client_event.client_id();
+            if let ClientEventKind::Read(LurkReadEvent::Character(character)) = client_event.event_mut() {
+                if let Err(refusal) = sandbox.dispatch(|ctx| store.restore(ctx, &world, client_id, character)) {
+                    deliver(&mut clients, refusal);
+                    continue;
+                }
+                // In play from here on, so fights, loot and saves find the player even
+                // if the module never calls `World:add_player`.
+                if let Err(e) = world.lock().add_player(client_id, character.clone()) {
+                    deliver(&mut clients, vec![(client_id, LurkWriteMessage::Error(e))]);
+                    continue;
+                }
+            }
+            if let ClientEventKind::Read(LurkReadEvent::Leave) = client_event.event() {
+                sandbox.dispatch(|ctx| store.save_client(ctx, &world, client_id));
+            }
+            if let Some(client) = clients.get(&client_id) {
+                match client_event.event() {
+                    ClientEventKind::Read(LurkReadEvent::Character(_)) => client.set_state(ConnectionState::Identified),
+                    ClientEventKind::Read(LurkReadEvent::Start) => client.set_state(ConnectionState::Playing),
+                    ClientEventKind::Read(LurkReadEvent::Leave) => client.set_state(ConnectionState::Leaving),
+                    _ => {}
+                }
             }
+
+            let outgoing = sandbox.dispatch(|ctx| handle_native(ctx, &world, &client_event));
+            deliver(&mut clients, outgoing);
+
+            let dispatched = sandbox.dispatch(|ctx| lua::dispatch_event(ctx, &client_event));
+            kv.finish_dispatch(dispatched.is_ok());
+            match dispatched {
+                Ok(true) => {}
+                Ok(false) => events_buffer.add(client_event),
+                Err(e) => error!(client = client_id; "Listener for '{}' failed: {}", lua::event_type(&client_event), e),
+            }
+        }
+
+        for client in departed {
+            if let Err(e) = sandbox.dispatch(|ctx| lua::forget_session(ctx, client.id())) {
+                error!(client = client.id(); "Failed to drop session of client: {}", e);
+            }
+            if client.kicked() {
+                client.close();
+            }
+        }
+
+        let ticked = sandbox.dispatch(|ctx| {
+            if let Some(on_tick) = module::hook(ctx, "on_tick") {
+                if let Err(e) = on_tick.call::<_, ()>(()) {
+                    error!("Hook 'on_tick' failed: {}", e);
+                    return false;
+                }
+            }
+            true
+        });
+        kv.finish_dispatch(ticked);
+        deliver(&mut clients, globals.outbox.take());
+
+        world.lock().tick += 1;
+        let outgoing = sandbox.dispatch(|ctx| monster::tick(ctx, &world));
+        deliver(&mut clients, outgoing);
+        deliver(&mut clients, regen::tick(&world));
+
+        if world.lock().every(config.server.save_interval) {
+            sandbox.dispatch(|ctx| store.save_all(ctx, &world));
+        }
+        kv.finish_dispatch(true);
+
+        running = !signal::terminating();
+        if let Some(remaining) = tick.checked_sub(tick_start.elapsed()).filter(|_| running) {
+            std::thread::sleep(remaining);
+        }
+    }
+
+    network.stop_accepting();
+    let grace = std::time::Duration::from_millis(config.server.shutdown_grace_ms);
+    shut_down(&sandbox, &store, &globals, &network, clients, tick, grace);
+}
+
+/// Binds every address the server was configured to listen on.
+fn bind(config: &NetworkConfig) -> Result<Vec<Listener>, String> {
+    let public = config.listen.iter().map(|address| (*address, false));
+    let trusted = config.trusted_listen.iter().map(|address| (*address, true));
+    public.chain(trusted)
+        .map(|(address, trusted)| {
+            let listener = Listener::bind(address, config.ipv6_only, trusted)
+                .map_err(|e| format!("failed to listen on '{}': {}", address, e))?;
+            info!(trusted = trusted; "Listening on {}.", address);
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Exits with the reason the configuration can't be served, as `main` does for
+/// one that doesn't add up.
+fn exit_invalid<T>(reason: String) -> T {
+    eprintln!("Invalid configuration: {}", reason);
+    std::process::exit(1);
+}
+
+/// Runs `on_shutdown`, saves every character in play, then kicks every client with
+/// a notice and waits up to the grace period for the notices to be delivered. If
+/// `on_shutdown` returns a string, it's sent in place of the default notice.
+fn shut_down(sandbox: &Sandbox, store: &CharacterStore, shared: &Globals, network: &Network,
+             mut clients: HashMap<u128, Client>, tick: std::time::Duration, grace: std::time::Duration) {
+    let deadline = std::time::Instant::now() + grace;
+    info!("Shutting down.");
+
+    let notice = sandbox.dispatch(|ctx| {
+        let notice = match module::hook(ctx, "on_shutdown") {
+            Some(on_shutdown) => match on_shutdown.call::<_, Option<rlua::String>>(()) {
+                Ok(notice) => notice.map(|notice| notice.as_bytes().to_vec()),
+                Err(e) => {
+                    error!("Hook 'on_shutdown' failed: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        store.save_all(ctx, &shared.world);
+        notice
+    });
+    shared.kv.finish_dispatch(true);
+
+    let notice = protocol::Error {
+        code: protocol::Error::OTHER,
+        message: notice.unwrap_or_else(|| b"The server is shutting down.".to_vec()),
+    };
+    while let Some(inbound) = network.poll() {
+        if let Inbound::Joined(client) = inbound {
+            clients.insert(client.id(), client);
+        }
+    }
+    for (_, mut client) in clients {
+        if !client.poisoned() {
+            client.kick(notice.clone());
+            client.close();
+        }
+    }
+
+    while network.connections() > 0 && std::time::Instant::now() < deadline {
+        std::thread::sleep(tick.min(deadline.saturating_duration_since(std::time::Instant::now())));
+    }
+}
+
+/// Kicks clients that have been silent for longer than their state allows. Once per
+/// silence, a client that's about to be kicked is handed to `on_idle(id, seconds)`
+/// first, and a string it returns is narrated to the client as a warning.
+fn check_idle(sandbox: &Sandbox, shared: &Globals, clients: &mut HashMap<u128, Client>, timeouts: &IdleTimeouts) {
+    let mut outgoing = Outgoing::new();
+    for client in clients.values_mut() {
+        if client.poisoned() || client.kicked() {
+            continue;
+        }
+        let limit = match timeouts.limit(client.state()) {
+            Some(limit) => limit,
+            None => continue,
+        };
+        let idle = client.idle();
+        if idle >= limit {
+            info!(client = client.id(); "Client was idle for {} seconds, disconnecting it.", idle.as_secs());
+            client.kick(protocol::Error::new(protocol::Error::OTHER, "Disconnected for inactivity."));
+            continue;
+        }
+        if idle + timeouts.warning < limit || !client.take_warning() {
+            continue;
         }
 
-        lua.context(|ctx| {
-            ctx.load(&script_src)
-                .set_name("main")
-                .unwrap()
-                .exec()
-                .unwrap();
+        let id = client.id();
+        let warned = sandbox.dispatch(|ctx| match module::hook(ctx, "on_idle") {
+            Some(on_idle) => on_idle.call::<_, Option<rlua::String>>((id, idle.as_secs()))
+                .map(|warning| warning.map(|warning| warning.as_bytes().to_vec())),
+            None => Ok(None),
         });
+        shared.kv.finish_dispatch(warned.is_ok());
+        match warned {
+            Ok(Some(warning)) => shared.world.lock().tell(id, &warning, &mut outgoing),
+            Ok(None) => {}
+            Err(e) => error!(client = id; "Hook 'on_idle' failed: {}", e),
+        }
+    }
+    deliver(clients, outgoing);
+}
+
+/// Server state shared with every Lua state the module runs in.
+struct Globals {
+    events: ClientEventBuffer,
+    outbox: ClientWriteBuffer,
+    world: WorldHandle,
+    kv: StoreHandle,
+    sessions: Sessions,
+}
+
+/// Builds a Lua state with the server's globals and runs the module's 'main.lua' in it.
+fn start_module(module: &str, limits: &Limits, shared: &Globals) -> Result<Sandbox, String> {
+    let sandbox = Sandbox::new(limits);
+    sandbox.dispatch(|ctx| -> Result<(), String> {
+        let globals = ctx.globals();
+        globals.set("Events", shared.events.clone())
+            .and_then(|_| globals.set("World", shared.world.clone()))
+            .and_then(|_| globals.set("Store", shared.kv.clone()))
+            .and_then(|_| lua::install_protocol_types(ctx))
+            .and_then(|_| lua::install_clients(ctx, &shared.sessions, &shared.world, &shared.outbox))
+            .and_then(|_| lua::install_log(ctx))
+            .map_err(|e| e.to_string())?;
+        module::load_main(ctx, module)?;
+        for problem in module::missing_hooks(ctx) {
+            warn!("Module '{}': {}", module, problem);
+        }
+        Ok(())
+    })?;
+    Ok(sandbox)
+}
+
+/// Swaps the running module for a fresh load of it, handing the new state a
+/// snapshot of the old one's globals through `on_reload(snapshot)` and carrying
+/// over session scratch tables. Monsters the module added with `World:add_monster`
+/// are taken out first, for its top level to add again. If loading or `on_reload`
+/// fails, the world is put back as it was and the old state keeps running.
+fn reload(module: &str, limits: &Limits, shared: &Globals, sandbox: &mut Sandbox) {
+    let (world, kv) = (&shared.world, &shared.kv);
+    let snapshots = sandbox.dispatch(|ctx| -> rlua::Result<_> {
+        Ok((Snapshot::take(ctx)?, Snapshot::of(lua::session_scratch(ctx)?)?))
+    });
+    let (snapshot, scratch) = match snapshots {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            error!("Failed to reload module '{}', couldn't snapshot the running state: {}", module, e);
+            return;
+        }
+    };
+    let backup = world.lock().clone();
+    world.lock().remove_added_monsters();
+
+    let result = start_module(module, limits, shared).and_then(|fresh| {
+        fresh.dispatch(|ctx| {
+            lua::restore_session_scratch(ctx, scratch.to_lua(ctx)?)?;
+            match module::hook(ctx, "on_reload") {
+                Some(on_reload) => on_reload.call::<_, ()>(snapshot.to_lua(ctx)?),
+                None => Ok(()),
+            }
+        })
+        .map_err(|e| format!("hook 'on_reload' failed: {}", e))?;
+        Ok(fresh)
+    });
+    kv.finish_dispatch(result.is_ok());
+
+    match result {
+        Ok(fresh) => {
+            *sandbox = fresh;
+            info!("Reloaded module '{}'.", module);
+        }
+        Err(e) => {
+            *world.lock() = backup;
+            error!("Failed to reload module '{}', keeping the running version: {}", module, e);
+        }
+    }
+}
+
+fn reload_access(access: &AccessHandle) {
+    if let Some(path) = access.path() {
+        match access.reload() {
+            Ok(()) => info!("Reloaded access list '{}'.", path.display()),
+            Err(e) => error!("Failed to reload access list '{}', keeping the old one: {}", path.display(), e),
+        }
+    }
+}
+
+/// Protocol messages the server resolves itself before the module sees them.
+fn handle_native(ctx: Context, world: &WorldHandle, client_event: &ClientEvent) -> Outgoing {
+    match client_event.event() {
+        ClientEventKind::Read(LurkReadEvent::Fight) => combat::fight(ctx, world, client_event.client_id()),
+        ClientEventKind::Read(LurkReadEvent::PVPFight(pvpfight)) => {
+            combat::pvp_fight(ctx, world, client_event.client_id(), &pvpfight.target)
+        }
+        ClientEventKind::Read(LurkReadEvent::Loot(lurkloot)) => {
+            loot::loot(ctx, world, client_event.client_id(), &lurkloot.target)
+        }
+        _ => Outgoing::new(),
+    }
+}
+
+fn deliver(clients: &mut HashMap<u128, Client>, outgoing: Outgoing) {
+    for (client_id, lurkmsg) in outgoing {
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.send(lurkmsg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::LurkName;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A module directory of its own for each test, with `main` as its 'main.lua'.
+    fn scratch(name: &str, main: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurk_world_server_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(module::main_script_path(dir.to_str().unwrap()), main).unwrap();
+        dir
+    }
+
+    fn globals(dir: &std::path::Path) -> Globals {
+        Globals {
+            events: ClientEventBuffer::default(),
+            outbox: ClientWriteBuffer::default(),
+            world: WorldHandle::default(),
+            kv: StoreHandle::open(&dir.join(crate::store::STORE_FILE)).unwrap(),
+            sessions: Sessions::default(),
+        }
+    }
+
+    #[test]
+    fn reloading_re_adds_the_modules_monsters() {
+        let dir = scratch("reload_monsters", r#"
+            World:add_monster(Character.new{ name = "Rat", health = 5 })
+            function on_tick() end
+            function on_reload(old) reloaded = true end
+        "#);
+        let module = dir.to_str().unwrap();
+        let (limits, shared) = (Limits::default(), globals(&dir));
+        let mut sandbox = start_module(module, &limits, &shared).unwrap();
+        shared.world.lock().get_mut(&LurkName::new("Rat")).unwrap().character.health = 1;
+
+        reload(module, &limits, &shared, &mut sandbox);
+        let reloaded = sandbox.dispatch(|ctx| ctx.globals().get::<_, bool>("reloaded")).unwrap();
+        assert!(reloaded);
+        assert_eq!(shared.world.lock().get(&LurkName::new("Rat")).unwrap().character.health, 5);
+    }
+
+    #[test]
+    fn a_failed_reload_keeps_the_world() {
+        let dir = scratch("reload_failed", r#"
+            World:add_monster(Character.new{ name = "Rat", health = 5 })
+            function on_tick() end
+        "#);
+        let module = dir.to_str().unwrap();
+        let (limits, shared) = (Limits::default(), globals(&dir));
+        let mut sandbox = start_module(module, &limits, &shared).unwrap();
+        shared.world.lock().get_mut(&LurkName::new("Rat")).unwrap().character.health = 1;
+
+        fs::write(module::main_script_path(module), "this is not lua").unwrap();
+        reload(module, &limits, &shared, &mut sandbox);
+        assert_eq!(shared.world.lock().get(&LurkName::new("Rat")).unwrap().character.health, 1);
     }
 }
@@ -0,0 +1,123 @@
+use crate::access;
+use crate::net::QueueDepth;
+use crate::protocol::Error;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, SystemTime};
+
+/// How far a client has got through the protocol.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// Connected but hasn't had a character accepted yet.
+    Connected,
+    /// Has a character but hasn't sent Start.
+    Identified,
+    Playing,
+    /// Sent Leave and is waiting to be disconnected.
+    Leaving,
+}
+
+impl ConnectionState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ConnectionState::Connected => "connected",
+            ConnectionState::Identified => "identified",
+            ConnectionState::Playing => "playing",
+            ConnectionState::Leaving => "leaving",
+        }
+    }
+}
+
+/// How long a client may go without sending anything, by how far it has got.
+/// `None` is no limit.
+#[derive(Clone, Copy)]
+pub struct IdleTimeouts {
+    /// Until the client sends Start, and once it has sent Leave.
+    pub lobby: Option<Duration>,
+    pub playing: Option<Duration>,
+    /// How long before the disconnect the module gets to warn the player.
+    pub warning: Duration,
+}
+
+impl IdleTimeouts {
+    pub fn limit(&self, state: ConnectionState) -> Option<Duration> {
+        match state {
+            ConnectionState::Playing => self.playing,
+            _ => self.lobby,
+        }
+    }
+}
+
+/// The module asking for a client to be dropped. The server acts on it at the
+/// start of its next tick.
+pub enum Disconnect {
+    /// Send the error, then close the connection gracefully.
+    Kick(Error),
+    /// Drop the client without a word.
+    Poison,
+}
+
+pub struct Session {
+    pub peer: Option<SocketAddr>,
+    /// Whether the client came in through a trusted listener.
+    pub trusted: bool,
+    pub connected_at: SystemTime,
+    pub state: ConnectionState,
+    pub disconnect: Option<Disconnect>,
+    pub queue: Arc<QueueDepth>,
+}
+
+pub type SessionHandle = Arc<Mutex<Session>>;
+
+/// Every live session by client id. Only a `Client` holds its session strongly,
+/// so the session is gone as soon as the client is dropped.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<u128, Weak<Mutex<Session>>>>>,
+}
+
+impl Sessions {
+    pub fn open(&self, id: u128, peer: Option<SocketAddr>, trusted: bool, queue: Arc<QueueDepth>) -> SessionHandle {
+        let session = Arc::new(Mutex::new(Session {
+            peer,
+            trusted,
+            connected_at: SystemTime::now(),
+            state: ConnectionState::Connected,
+            disconnect: None,
+            queue,
+        }));
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.strong_count() > 0);
+        sessions.insert(id, Arc::downgrade(&session));
+        session
+    }
+
+    pub fn get(&self, id: u128) -> Option<Weak<Mutex<Session>>> {
+        self.sessions.lock().unwrap().get(&id)
+            .filter(|session| session.strong_count() > 0)
+            .cloned()
+    }
+
+    /// Ids of every live session, oldest first.
+    pub fn ids(&self) -> Vec<u128> {
+        let mut ids: Vec<u128> = self.sessions.lock().unwrap().iter()
+            .filter(|(_, session)| session.strong_count() > 0)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Live sessions connected from the address, whether it came as IPv4 or as
+    /// IPv4 mapped to IPv6.
+    pub fn count_from(&self, ip: IpAddr) -> usize {
+        let ip = access::canonical(ip);
+        let live: Vec<SessionHandle> = self.sessions.lock().unwrap().values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        live.iter()
+            .filter(|session| session.lock().unwrap().peer.map(|peer| access::canonical(peer.ip())) == Some(ip))
+            .count()
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+/// Starts recording signals the server loop acts on. Handlers only set a flag,
+/// which the loop picks up with `take_hangup` or `terminating` at tick boundaries.
+///
+/// SIGINT and SIGTERM ask for a graceful shutdown; a second one while shutting
+/// down exits on the spot.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn on_hangup(_: libc::c_int) {
+        HANGUP.store(true, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_terminate(_: libc::c_int) {
+        if TERMINATE.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(1) };
+        }
+    }
+
+    type Handler = extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGHUP, on_hangup as Handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_terminate as Handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_terminate as Handler as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Whether a SIGHUP arrived since the last call.
+pub fn take_hangup() -> bool {
+    HANGUP.swap(false, Ordering::SeqCst)
+}
+
+/// Whether the server has been asked to shut down.
+pub fn terminating() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
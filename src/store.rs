@@ -0,0 +1,334 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const STORE_FILE: &str = "store.journal";
+
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+const TAG_STRING: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_BOOLEAN: u8 = 4;
+
+#[derive(Clone, PartialEq)]
+pub enum StoredValue {
+    String(Vec<u8>),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+}
+
+/// Durable key-value storage for modules. Every change is appended to a journal
+/// which is replayed on open, and compacted into a fresh journal when it has
+/// grown well past the live data.
+///
+/// Changes are staged until `commit`, so a dispatch that fails part way can be
+/// thrown away with `rollback`. Reads see staged changes.
+pub struct KeyValueStore {
+    path: PathBuf,
+    data: BTreeMap<Vec<u8>, StoredValue>,
+    pending: BTreeMap<Vec<u8>, Option<StoredValue>>,
+    journal: File,
+    /// Bytes of the journal holding committed records.
+    len: u64,
+    records: usize,
+}
+
+impl KeyValueStore {
+    pub fn open(path: &Path) -> io::Result<KeyValueStore> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut data = BTreeMap::new();
+        let mut records = 0;
+        if path.exists() {
+            let bytes = fs::read(path)?;
+            let mut source = &bytes[..];
+            // A torn record at the end is from a crash mid-append; everything
+            // before it was committed. It's cut off so new records follow the
+            // last good one rather than the garbage.
+            let mut good = 0;
+            while let Ok((key, value)) = read_record(&mut source) {
+                match value {
+                    Some(value) => data.insert(key, value),
+                    None => data.remove(&key),
+                };
+                records += 1;
+                good = bytes.len() - source.len();
+            }
+            if good < bytes.len() {
+                warn!("Dropping {} bytes of a torn record from the end of '{}'.", bytes.len() - good, path.display());
+                OpenOptions::new().write(true).open(path)?.set_len(good as u64)?;
+            }
+        }
+
+        if records > 64 && records > data.len() * 2 {
+            write_snapshot(path, &data)?;
+            records = data.len();
+        }
+
+        let journal = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = journal.metadata()?.len();
+        Ok(KeyValueStore {
+            path: path.to_path_buf(),
+            data,
+            pending: BTreeMap::new(),
+            journal,
+            len,
+            records,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&StoredValue> {
+        match self.pending.get(key) {
+            Some(staged) => staged.as_ref(),
+            None => self.data.get(key),
+        }
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: StoredValue) {
+        self.pending.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.pending.insert(key, None);
+    }
+
+    /// Every live entry whose key starts with the prefix, in key order.
+    pub fn scan(&self, prefix: &[u8]) -> Vec<(Vec<u8>, StoredValue)> {
+        let mut merged: BTreeMap<&[u8], &StoredValue> = self.data
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (&key[..], value))
+            .collect();
+        for (key, staged) in self.pending.range(prefix.to_vec()..).take_while(|(key, _)| key.starts_with(prefix)) {
+            match staged {
+                Some(value) => merged.insert(&key[..], value),
+                None => merged.remove(&key[..]),
+            };
+        }
+        merged.into_iter().map(|(key, value)| (key.to_vec(), value.clone())).collect()
+    }
+
+    /// Appends the staged changes to the journal. If that fails the journal is cut
+    /// back to where it was and the changes stay staged for the next commit.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut bytes: Vec<u8> = vec![];
+        for (key, staged) in self.pending.iter() {
+            write_record(&mut bytes, key, staged.as_ref())?;
+        }
+        let written = self.journal.write_all(&bytes).and_then(|_| self.journal.sync_data());
+        if let Err(e) = written {
+            // Best effort: if this fails too, the partial record is a torn tail
+            // that the next open cuts off.
+            let _ = self.journal.set_len(self.len);
+            return Err(e);
+        }
+        self.len += bytes.len() as u64;
+
+        let pending = std::mem::take(&mut self.pending);
+        self.records += pending.len();
+        for (key, staged) in pending {
+            match staged {
+                Some(value) => self.data.insert(key, value),
+                None => self.data.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn rollback(&mut self) {
+        self.pending.clear();
+    }
+}
+
+fn write_record<W: Write>(out: &mut W, key: &[u8], value: Option<&StoredValue>) -> io::Result<()> {
+    out.write_u8(if value.is_some() { OP_SET } else { OP_DELETE })?;
+    out.write_u32::<LittleEndian>(key.len() as u32)?;
+    out.write_all(key)?;
+    match value {
+        Some(StoredValue::String(bytes)) => {
+            out.write_u8(TAG_STRING)?;
+            out.write_u32::<LittleEndian>(bytes.len() as u32)?;
+            out.write_all(bytes)?;
+        }
+        Some(StoredValue::Integer(i)) => {
+            out.write_u8(TAG_INTEGER)?;
+            out.write_i64::<LittleEndian>(*i)?;
+        }
+        Some(StoredValue::Number(n)) => {
+            out.write_u8(TAG_NUMBER)?;
+            out.write_f64::<LittleEndian>(*n)?;
+        }
+        Some(StoredValue::Boolean(b)) => {
+            out.write_u8(TAG_BOOLEAN)?;
+            out.write_u8(*b as u8)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+fn read_record(source: &mut &[u8]) -> io::Result<(Vec<u8>, Option<StoredValue>)> {
+    let op = source.read_u8()?;
+    let key_len = source.read_u32::<LittleEndian>()?;
+    let mut key = vec![0u8; key_len as usize];
+    source.read_exact(&mut key)?;
+    if op == OP_DELETE {
+        return Ok((key, None));
+    }
+
+    let value = match source.read_u8()? {
+        TAG_STRING => {
+            let len = source.read_u32::<LittleEndian>()?;
+            let mut bytes = vec![0u8; len as usize];
+            source.read_exact(&mut bytes)?;
+            StoredValue::String(bytes)
+        }
+        TAG_INTEGER => StoredValue::Integer(source.read_i64::<LittleEndian>()?),
+        TAG_NUMBER => StoredValue::Number(source.read_f64::<LittleEndian>()?),
+        TAG_BOOLEAN => StoredValue::Boolean(source.read_u8()? != 0),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown value tag")),
+    };
+    Ok((key, Some(value)))
+}
+
+fn write_snapshot(path: &Path, data: &BTreeMap<Vec<u8>, StoredValue>) -> io::Result<()> {
+    let temp = path.with_extension("compact");
+    {
+        let mut out = BufWriter::new(File::create(&temp)?);
+        for (key, value) in data.iter() {
+            write_record(&mut out, key, Some(value))?;
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+    }
+    fs::rename(&temp, path)
+}
+
+#[derive(Clone)]
+pub struct StoreHandle {
+    store: Arc<Mutex<KeyValueStore>>,
+}
+
+impl StoreHandle {
+    pub fn open(path: &Path) -> io::Result<StoreHandle> {
+        Ok(StoreHandle {
+            store: Arc::new(Mutex::new(KeyValueStore::open(path)?)),
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, KeyValueStore> {
+        self.store.lock().unwrap()
+    }
+
+    /// Ends a dispatch: commits what it staged if it succeeded, discards it otherwise.
+    pub fn finish_dispatch(&self, succeeded: bool) {
+        let mut store = self.lock();
+        if !succeeded {
+            store.rollback();
+        } else if let Err(e) = store.commit() {
+            error!("Failed to commit store '{}': {}", store.path().display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A journal path of its own for each test, emptied of anything a past run left.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurk_world_store_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(STORE_FILE)
+    }
+
+    fn integer(store: &KeyValueStore, key: &[u8]) -> Option<i64> {
+        match store.get(key) {
+            Some(StoredValue::Integer(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn torn_tail_is_cut_before_new_records() {
+        let path = scratch("torn");
+        {
+            let mut store = KeyValueStore::open(&path).unwrap();
+            store.set(b"a".to_vec(), StoredValue::Integer(1));
+            store.commit().unwrap();
+        }
+        let good = fs::metadata(&path).unwrap().len();
+        // Half a record, as a crash mid-append leaves it.
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[OP_SET, 5, 0]).unwrap();
+
+        {
+            let mut store = KeyValueStore::open(&path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), good);
+            assert_eq!(integer(&store, b"a"), Some(1));
+            store.set(b"b".to_vec(), StoredValue::Integer(2));
+            store.commit().unwrap();
+        }
+
+        let store = KeyValueStore::open(&path).unwrap();
+        assert_eq!(integer(&store, b"a"), Some(1));
+        assert_eq!(integer(&store, b"b"), Some(2));
+    }
+
+    #[test]
+    fn rolled_back_changes_are_never_written() {
+        let path = scratch("rollback");
+        {
+            let mut store = KeyValueStore::open(&path).unwrap();
+            store.set(b"a".to_vec(), StoredValue::Integer(1));
+            assert_eq!(integer(&store, b"a"), Some(1));
+            store.rollback();
+            assert_eq!(integer(&store, b"a"), None);
+            store.commit().unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn overwritten_journal_compacts_to_a_snapshot() {
+        let path = scratch("compact");
+        {
+            let mut store = KeyValueStore::open(&path).unwrap();
+            for i in 0..100 {
+                store.set(b"counter".to_vec(), StoredValue::Integer(i));
+                store.commit().unwrap();
+            }
+            store.set(b"gone".to_vec(), StoredValue::Boolean(true));
+            store.commit().unwrap();
+            store.delete(b"gone".to_vec());
+            store.commit().unwrap();
+        }
+        let before = fs::metadata(&path).unwrap().len();
+
+        let store = KeyValueStore::open(&path).unwrap();
+        let after = fs::metadata(&path).unwrap().len();
+        assert!(after < before);
+        assert_eq!(store.records, 1);
+        assert_eq!(integer(&store, b"counter"), Some(99));
+        assert!(store.get(b"gone").is_none());
+        assert!(!path.with_extension("compact").exists());
+
+        let store = KeyValueStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), after);
+        assert_eq!(integer(&store, b"counter"), Some(99));
+    }
+}
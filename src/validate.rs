@@ -0,0 +1,169 @@
+use crate::cli::ValidateArgs;
+use crate::config::{Config, LuaConfig};
+use crate::lua::{self, ClientEventBuffer, ClientWriteBuffer};
+use crate::module;
+use crate::sandbox::{Limits, Sandbox};
+use crate::session::Sessions;
+use crate::world::WorldHandle;
+use rlua::{Context, Lua};
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub struct Diagnostic {
+    file: PathBuf,
+    message: String,
+    /// Worth fixing, but the module runs regardless.
+    warning: bool,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.warning { "warning" } else { "error" };
+        write!(f, "{}: {}: {}", severity, self.file.display(), self.message)
+    }
+}
+
+/// Checks a module without starting the server, printing every problem found.
+/// Returns `true` if the module is usable.
+pub fn validate(args: &ValidateArgs) -> bool {
+    let lua = match &args.config {
+        Some(path) => match Config::load(Path::new(path)) {
+            Ok(config) => config.lua,
+            Err(e) => {
+                eprintln!("Invalid configuration: {}", e);
+                return false;
+            }
+        },
+        None => LuaConfig::default(),
+    };
+    let diagnostics = check_module(&args.module, &lua.limits());
+
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}", diagnostic);
+    }
+
+    let errors = diagnostics.iter().filter(|diagnostic| !diagnostic.warning).count();
+    if errors == 0 {
+        println!("Module '{}' is valid.", args.module);
+        true
+    } else {
+        eprintln!("Module '{}' has {} problem(s).", args.module, errors);
+        false
+    }
+}
+
+pub fn check_module(module: &str, limits: &Limits) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let main_path = module::main_script_path(module);
+
+    // Checked under the limits the server would use, so a runaway top level fails here too.
+    // The top level still runs, and may `require` other files from the module directory.
+    let sandbox = Sandbox::new(limits);
+
+    sandbox.dispatch(|ctx| {
+        let globals = ctx.globals();
+        let installed = globals.set("Events", ClientEventBuffer::default())
+            .and_then(|_| globals.set("World", WorldHandle::default()))
+            .and_then(|_| lua::install_protocol_types(ctx))
+            .and_then(|_| lua::install_clients(ctx, &Sessions::default(), &WorldHandle::default(), &ClientWriteBuffer::default()))
+            .and_then(|_| lua::install_log(ctx));
+        if let Err(e) = installed {
+            diagnostics.push(Diagnostic { file: main_path.clone(), message: e.to_string(), warning: false });
+            return;
+        }
+
+        if let Err(message) = module::load_main(ctx, module) {
+            diagnostics.push(Diagnostic { file: main_path.clone(), message, warning: false });
+            return;
+        }
+
+        check_hooks(ctx, &main_path, &mut diagnostics);
+    });
+
+    let mut data_files = vec![];
+    collect_lua_files(Path::new(module), &mut data_files, &mut diagnostics);
+    for path in data_files.iter().filter(|path| **path != main_path) {
+        check_data_file(sandbox.lua(), path, &mut diagnostics);
+    }
+
+    diagnostics
+}
+
+fn check_hooks(ctx: Context, main_path: &Path, diagnostics: &mut Vec<Diagnostic>) {
+    for message in module::missing_hooks(ctx) {
+        diagnostics.push(Diagnostic { file: main_path.to_path_buf(), message, warning: true });
+    }
+}
+
+/// World data files are Lua chunks shipped alongside 'main.lua'. They're compiled
+/// but not run, so syntax errors surface before the server loads them.
+fn check_data_file(lua: &Lua, path: &Path, diagnostics: &mut Vec<Diagnostic>) {
+    use std::fs::read_to_string;
+
+    let src = match read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => {
+            diagnostics.push(Diagnostic { file: path.to_path_buf(), message: e.to_string(), warning: false });
+            return;
+        }
+    };
+
+    lua.context(|ctx| {
+        let name = path.display().to_string();
+        let compiled = ctx.load(&src)
+            .set_name(&name)
+            .and_then(|chunk| chunk.into_function());
+        if let Err(e) = compiled {
+            diagnostics.push(Diagnostic { file: path.to_path_buf(), message: e.to_string(), warning: false });
+        }
+    });
+}
+
+fn collect_lua_files(dir: &Path, files: &mut Vec<PathBuf>, diagnostics: &mut Vec<Diagnostic>) {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) => {
+            diagnostics.push(Diagnostic { file: dir.to_path_buf(), message: e.to_string(), warning: false });
+            return;
+        }
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_lua_files(&path, files, diagnostics);
+        } else if path.extension() == Some(OsStr::new("lua")) {
+            files.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A module directory of its own for each test, with `main` as its 'main.lua'.
+    fn scratch(name: &str, main: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurk_world_validate_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(module::main_script_path(dir.to_str().unwrap()), main).unwrap();
+        dir
+    }
+
+    fn errors(diagnostics: &[Diagnostic]) -> usize {
+        diagnostics.iter().filter(|diagnostic| !diagnostic.warning).count()
+    }
+
+    #[test]
+    fn modules_are_checked_under_the_given_limits() {
+        let dir = scratch("limits", "for i = 1, 100000 do end function on_tick() end");
+        let module = dir.to_str().unwrap();
+        assert_eq!(errors(&check_module(module, &Limits::default())), 0);
+
+        let tight = Limits { instructions: Some(10_000), memory: None };
+        assert_eq!(errors(&check_module(module, &tight)), 1);
+    }
+}
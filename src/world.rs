@@ -0,0 +1,241 @@
+use crate::combat::CombatRules;
+use crate::loot::LootRules;
+use crate::monster::Monsters;
+use crate::regen::RegenRules;
+use crate::protocol::{Character, CharacterFlags, Error, Game, LurkName, Message};
+use crate::write::LurkWriteMessage;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Messages produced by a native game system, addressed by client id.
+pub type Outgoing = Vec<(u128, LurkWriteMessage)>;
+
+/// Answers a client's request with an error and nothing else.
+pub fn reject(client_id: u128, code: u8, reason: &str) -> Outgoing {
+    vec![(client_id, LurkWriteMessage::Error(Error::new(code, reason)))]
+}
+
+#[derive(Clone)]
+pub struct Entity {
+    pub character: Character,
+    /// The client playing this character, `None` for monsters.
+    pub client_id: Option<u128>,
+    pub max_health: i16,
+    /// The tick this character last took part in a fight.
+    pub last_fought: Option<u64>,
+}
+
+impl Entity {
+    pub fn is_alive(&self) -> bool {
+        self.character.flags.contains(CharacterFlags::ALIVE)
+    }
+
+    pub fn is_monster(&self) -> bool {
+        self.character.flags.contains(CharacterFlags::MONSTER)
+    }
+
+    pub fn room(&self) -> u16 {
+        self.character.current_room_number
+    }
+}
+
+/// Every character in play, players and monsters alike, keyed by name.
+#[derive(Clone, Default)]
+pub struct World {
+    entities: HashMap<LurkName, Entity>,
+    pub combat: CombatRules,
+    pub loot: LootRules,
+    pub regen: RegenRules,
+    pub monsters: Monsters,
+    /// The Game message from the server's configuration.
+    pub game: Game,
+    /// Server ticks since startup.
+    pub tick: u64,
+    connections: HashMap<u16, Vec<u16>>,
+}
+
+impl World {
+    /// Puts a client's character into play, replacing whichever one the client had
+    /// before. Fails if anyone or anything else already goes by the name.
+    ///
+    /// The server calls this for every Character it accepts; a module calls it
+    /// through `World:add_player` to replace that with a character of its own.
+    pub fn add_player(&mut self, client_id: u128, character: Character) -> Result<(), Error> {
+        self.check_name(client_id, &character.name)?;
+        self.remove_client(client_id);
+        let max_health = character.health;
+        self.entities.insert(character.name, Entity {
+            character,
+            client_id: Some(client_id),
+            max_health,
+            last_fought: None,
+        });
+        Ok(())
+    }
+
+    /// Whether a client may play a character by this name, giving the error to
+    /// answer it with if not.
+    pub fn check_name(&self, client_id: u128, name: &LurkName) -> Result<(), Error> {
+        match self.entities.get(name) {
+            Some(entity) if entity.client_id != Some(client_id) => {
+                Err(Error::new(Error::PLAYER_EXISTS, "That name is already in play."))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Puts a monster into play. Fails if anything already goes by its name.
+    pub fn add_monster(&mut self, mut character: Character) -> Result<(), Error> {
+        if self.entities.contains_key(&character.name) {
+            return Err(Error::new(Error::PLAYER_EXISTS, "That name is already in play."));
+        }
+        character.flags.insert(CharacterFlags::MONSTER);
+        let max_health = character.health;
+        self.entities.insert(character.name, Entity {
+            character,
+            client_id: None,
+            max_health,
+            last_fought: None,
+        });
+        Ok(())
+    }
+
+    /// Takes out the monsters the module added itself, so a reloaded module's top
+    /// level can add them again. Players and spawned monsters stay where they are.
+    pub fn remove_added_monsters(&mut self) {
+        let monsters = &self.monsters;
+        self.entities.retain(|name, entity| !entity.is_monster() || monsters.spawns(name));
+    }
+
+    pub fn remove(&mut self, name: &LurkName) -> Option<Entity> {
+        self.entities.remove(name)
+    }
+
+    pub fn remove_client(&mut self, client_id: u128) {
+        self.entities.retain(|_, entity| entity.client_id != Some(client_id));
+    }
+
+    pub fn get(&self, name: &LurkName) -> Option<&Entity> {
+        self.entities.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &LurkName) -> Option<&mut Entity> {
+        self.entities.get_mut(name)
+    }
+
+    pub fn entities_mut(&mut self) -> impl Iterator<Item = &mut Entity> {
+        self.entities.values_mut()
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &Entity> {
+        self.entities.values().filter(|entity| entity.client_id.is_some())
+    }
+
+    pub fn player(&self, client_id: u128) -> Option<&Entity> {
+        self.entities.values().find(|entity| entity.client_id == Some(client_id))
+    }
+
+    pub fn in_room(&self, room: u16) -> impl Iterator<Item = &Entity> {
+        self.entities.values().filter(move |entity| entity.room() == room)
+    }
+
+    /// True on ticks that fall on the interval. A zero interval never fires.
+    pub fn every(&self, interval: u32) -> bool {
+        interval > 0 && self.tick % u64::from(interval) == 0
+    }
+
+    /// Records a one-way exit between two rooms.
+    pub fn connect(&mut self, from: u16, to: u16) {
+        let exits = self.connections.entry(from).or_default();
+        if !exits.contains(&to) {
+            exits.push(to);
+        }
+    }
+
+    pub fn connections(&self, room: u16) -> Vec<u16> {
+        self.connections.get(&room).cloned().unwrap_or_default()
+    }
+
+    /// Queues a message for every player in the room.
+    pub fn broadcast(&self, room: u16, lurkmsg: &LurkWriteMessage, out: &mut Outgoing) {
+        for entity in self.in_room(room) {
+            if let Some(client_id) = entity.client_id {
+                out.push((client_id, lurkmsg.clone()));
+            }
+        }
+    }
+
+    /// Queues a line of narration, addressed to each player in the room by name.
+    pub fn narrate(&self, room: u16, text: &str, out: &mut Outgoing) {
+        for entity in self.in_room(room) {
+            if let Some(client_id) = entity.client_id {
+                let message = Message {
+                    recipient: entity.character.name,
+                    sender: LurkName::new(NARRATOR),
+                    message: text.as_bytes().to_vec(),
+                };
+                out.push((client_id, LurkWriteMessage::Message(message)));
+            }
+        }
+    }
+
+    /// Queues a line of narration for one client, addressed to its character by name
+    /// if it has one.
+    pub fn tell(&self, client_id: u128, text: &[u8], out: &mut Outgoing) {
+        let recipient = self.player(client_id)
+            .map(|entity| entity.character.name)
+            .unwrap_or_default();
+        let message = Message {
+            recipient,
+            sender: LurkName::new(NARRATOR),
+            message: text.to_vec(),
+        };
+        out.push((client_id, LurkWriteMessage::Message(message)));
+    }
+}
+
+pub const NARRATOR: &str = "Narrator";
+
+#[derive(Clone, Default)]
+pub struct WorldHandle {
+    world: Arc<Mutex<World>>,
+}
+
+impl WorldHandle {
+    pub fn lock(&self) -> MutexGuard<'_, World> {
+        self.world.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character(name: &str) -> Character {
+        Character { name: LurkName::new(name), ..Character::default() }
+    }
+
+    #[test]
+    fn a_client_plays_one_character_at_a_time() {
+        let mut world = World::default();
+        assert!(world.add_player(1, character("Hero")).is_ok());
+        assert!(world.add_player(1, character("Heroine")).is_ok());
+        assert!(world.get(&LurkName::new("Hero")).is_none());
+        assert_eq!(world.player(1).map(|entity| entity.character.name.to_string_lossy()), Some("Heroine".to_string()));
+    }
+
+    #[test]
+    fn names_in_play_are_refused_to_others() {
+        let mut world = World::default();
+        assert!(world.add_player(1, character("Hero")).is_ok());
+        assert!(world.add_monster(character("Rat")).is_ok());
+
+        for name in &["Hero", "Rat"] {
+            let refused = world.add_player(2, character(name)).err().map(|e| e.code);
+            assert_eq!(refused, Some(Error::PLAYER_EXISTS));
+        }
+        assert!(world.add_monster(character("Hero")).is_err());
+        assert!(world.add_player(1, character("Hero")).is_ok());
+        assert_eq!(world.players().count(), 1);
+    }
+}
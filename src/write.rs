@@ -1,12 +1,12 @@
-use std::io::{BufWriter, Write};
+use std::io::Write;
 use crate::protocol::{Message, Error, Room, Character, Game, Connection, Accept, TypeCode, LurkName, Version};
 
 use std::io;
-use std::net::TcpStream;
 use byteorder::{WriteBytesExt, LittleEndian};
 
 pub type LurkWriteResult = io::Result<()>;
 
+#[derive(Clone)]
 pub enum LurkWriteMessage {
     Message(Message),
     Error(Error),
@@ -18,6 +18,24 @@ pub enum LurkWriteMessage {
     Version(Version),
 }
 
+impl LurkWriteMessage {
+    /// Bytes the message takes on the wire.
+    pub fn wire_size(&self) -> usize {
+        match self {
+            LurkWriteMessage::Message(msg) => 67 + msg.message.len(),
+            LurkWriteMessage::Error(err) => 4 + err.message.len(),
+            LurkWriteMessage::Accept(_) => 2,
+            LurkWriteMessage::Room(room) => 37 + room.description.len(),
+            LurkWriteMessage::Character(ch) => 48 + ch.description.len(),
+            LurkWriteMessage::Game(game) => 7 + game.description.len(),
+            LurkWriteMessage::Connection(conn) => 37 + conn.description.len(),
+            LurkWriteMessage::Version(version) => {
+                5 + version.extensions.iter().map(Vec::len).sum::<usize>()
+            }
+        }
+    }
+}
+
 pub trait LurkWrite {
     fn write_lurk_name(&mut self, name: &LurkName) -> LurkWriteResult;
     fn write_message(&mut self, msg: &Message) -> LurkWriteResult;
@@ -28,9 +46,10 @@ pub trait LurkWrite {
     fn write_game(&mut self, game: &Game) -> LurkWriteResult;
     fn write_connection(&mut self, conn: &Connection) -> LurkWriteResult;
     fn write_version(&mut self, version: &Version) -> LurkWriteResult;
+    fn write_lurk_message(&mut self, lurkmsg: &LurkWriteMessage) -> LurkWriteResult;
 }
 
-impl LurkWrite for BufWriter<TcpStream> {
+impl<W: Write> LurkWrite for W {
 
     fn write_lurk_name(&mut self, name: &LurkName) -> LurkWriteResult {
         self.write_all(&name.bytes)?;
@@ -112,4 +131,52 @@ impl LurkWrite for BufWriter<TcpStream> {
         }
         Ok(())
     }
+
+    fn write_lurk_message(&mut self, lurkmsg: &LurkWriteMessage) -> LurkWriteResult {
+        match lurkmsg {
+            LurkWriteMessage::Message(msg) => self.write_message(msg),
+            LurkWriteMessage::Error(err) => self.write_error(err),
+            LurkWriteMessage::Accept(accept) => self.write_accept(accept),
+            LurkWriteMessage::Room(room) => self.write_room(room),
+            LurkWriteMessage::Character(ch) => self.write_character(ch),
+            LurkWriteMessage::Game(game) => self.write_game(game),
+            LurkWriteMessage::Connection(conn) => self.write_connection(conn),
+            LurkWriteMessage::Version(version) => self.write_version(version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded_len(lurkmsg: &LurkWriteMessage) -> usize {
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_lurk_message(lurkmsg).unwrap();
+        bytes.len()
+    }
+
+    #[test]
+    fn wire_size_matches_what_is_written() {
+        let text = b"Something with a little length to it.".to_vec();
+        let messages = [
+            LurkWriteMessage::Message(Message { message: text.clone(), ..Message::default() }),
+            LurkWriteMessage::Error(Error::new(Error::OTHER, "Not like that.")),
+            LurkWriteMessage::Accept(Accept { code: 10 }),
+            LurkWriteMessage::Room(Room { description: text.clone(), ..Room::default() }),
+            LurkWriteMessage::Character(Character { description: text.clone(), ..Character::default() }),
+            LurkWriteMessage::Game(Game { description: text.clone(), ..Game::default() }),
+            LurkWriteMessage::Connection(Connection { description: text.clone(), ..Connection::default() }),
+            LurkWriteMessage::Version(Version { major: 2, minor: 3, extensions: vec![vec![0; 4], vec![1; 7]] }),
+        ];
+        for lurkmsg in messages.iter() {
+            assert_eq!(lurkmsg.wire_size(), encoded_len(lurkmsg));
+        }
+    }
+
+    #[test]
+    fn wire_size_of_empty_bodies() {
+        assert_eq!(LurkWriteMessage::Character(Character::default()).wire_size(), 48);
+        assert_eq!(LurkWriteMessage::Error(Error::new(Error::OTHER, "")).wire_size(), 4);
+    }
 }